@@ -0,0 +1,96 @@
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+
+use crate::Device;
+use crate::error::LedgerError;
+use crate::output::info;
+use crate::speculos::{APP_LOAD_ADDRESS, SpeculosArgs, run_speculos};
+
+/// Port of the Speculos GDB stub (not configurable in Speculos).
+const GDB_PORT: u16 = 1234;
+
+/// Build the `add-symbol-file` command loading `elf_path` where Speculos maps
+/// it. The app is position independent: Speculos maps its `.text` section at
+/// `APP_LOAD_ADDRESS`, and every read-only section following it in flash is
+/// shifted by the same offset. RAM sections are left at their link address.
+pub fn add_symbol_file_command(
+    elf_path: &Utf8Path,
+) -> Result<String, LedgerError> {
+    let buffer = fs::read(elf_path)?;
+    let elf = goblin::elf::Elf::parse(&buffer)?;
+
+    let text = elf
+        .section_headers
+        .iter()
+        .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(".text"))
+        .ok_or(LedgerError::MissingField(".text section"))?;
+    let offset = APP_LOAD_ADDRESS.wrapping_sub(text.sh_addr);
+
+    let mut cmd =
+        format!("add-symbol-file \"{elf_path}\" {:#x}", APP_LOAD_ADDRESS);
+    for section in elf.section_headers.iter() {
+        let Some(name) = elf.shdr_strtab.get_at(section.sh_name) else {
+            continue;
+        };
+        if name == ".text"
+            || !section.is_alloc()
+            || section.is_writable()
+            || section.sh_size == 0
+            || section.sh_addr < text.sh_addr
+        {
+            continue;
+        }
+        cmd.push_str(&format!(
+            " -s {name} {:#x}",
+            section.sh_addr.wrapping_add(offset)
+        ));
+    }
+    Ok(cmd)
+}
+
+/// Write a gdbinit and a VS Code launch configuration next to the ELF, then
+/// start Speculos waiting for a debugger.
+pub fn debug_app(
+    device: Device,
+    elf_path: &Utf8PathBuf,
+    api_level: &str,
+    args: &SpeculosArgs,
+) -> Result<(), LedgerError> {
+    let add_symbol_file = add_symbol_file_command(elf_path)?;
+
+    let gdbinit_path = elf_path.with_extension("gdbinit");
+    let gdbinit = format!(
+        "set architecture arm\n\
+         target remote 127.0.0.1:{GDB_PORT}\n\
+         handle SIGILL nostop pass noprint\n\
+         {add_symbol_file}\n"
+    );
+    fs::write(&gdbinit_path, gdbinit)?;
+
+    let launch_path = elf_path.with_extension("launch.json");
+    let launch = serde_json::json!({
+        "name": format!("Speculos {device}"),
+        "type": "cppdbg",
+        "request": "launch",
+        "program": elf_path,
+        "cwd": "${workspaceFolder}",
+        "MIMode": "gdb",
+        "miDebuggerPath": "gdb-multiarch",
+        "miDebuggerServerAddress": format!("127.0.0.1:{GDB_PORT}"),
+        "stopAtConnect": true,
+        "setupCommands": [
+            { "text": "set architecture arm" },
+            { "text": "handle SIGILL nostop pass noprint" },
+            { "text": add_symbol_file },
+        ],
+    });
+    let launch = serde_json::to_string_pretty(&launch)?;
+    fs::write(&launch_path, format!("{launch}\n"))?;
+
+    info!("GDB script written to {gdbinit_path}");
+    info!("VS Code launch configuration written to {launch_path}:");
+    info!("{launch}");
+    println!("Attach with: gdb-multiarch -q -nh -x {gdbinit_path}");
+
+    run_speculos(device, elf_path, api_level, args, true)
+}
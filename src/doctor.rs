@@ -0,0 +1,430 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+use crate::Device;
+use crate::error::{BINUTILS_FIX, LedgerError, PYTHON_FIX, RUSTUP_FIX};
+use crate::manifest::{SetupManifest, sha256_hex};
+use crate::process::output;
+use crate::runner::{Tool, which};
+use crate::setup::{
+    LINK_SCRIPT, OFFLINE, SpecCheck, check_target_spec, project_sdk_version,
+    project_target_path, project_targets_dir, rust_lld_dirs, rustc_output,
+    rustlib_dir, sysroot_target_path, target_fix_hint,
+};
+use crate::utils::{check_ledgerblue_version, ledgerblue_version};
+
+#[derive(clap::Args, Debug)]
+pub struct DoctorArgs {
+    #[clap(long)]
+    #[clap(help = "print the report as JSON, e.g. for bug reports")]
+    pub json: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+struct Check {
+    name: String,
+    status: Status,
+    /// Version, path or state found
+    detail: String,
+    /// How to fix the problem, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(
+        name: impl Into<String>,
+        detail: impl Into<String>,
+        fix: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            status: Status::Warning,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(
+        name: impl Into<String>,
+        detail: impl Into<String>,
+        fix: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            status: Status::Error,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    cargo_ledger_version: &'static str,
+    host: String,
+    checks: Vec<Check>,
+}
+
+/// Check the tools and files the other commands rely on, and suggest how to
+/// fix what is missing or broken.
+pub fn doctor(args: &DoctorArgs) -> Result<(), LedgerError> {
+    let mut checks = vec![
+        tool_check("cargo", &Tool::Cargo.program(), &["--version"], RUSTUP_FIX),
+        rustc_check(),
+        rust_src_check(),
+        tool_check(
+            "objcopy",
+            &Tool::Objcopy.program(),
+            &["--version"],
+            BINUTILS_FIX,
+        ),
+        tool_check("size", &Tool::Size.program(), &["--version"], BINUTILS_FIX),
+        tool_check(
+            "python",
+            &Tool::Python.program(),
+            &["--version"],
+            PYTHON_FIX,
+        ),
+        ledgerblue_check(),
+    ];
+    // Tools selected for a given device with CARGO_TARGET_<DEVICE>_<TOOL>
+    for &device in Device::value_variants() {
+        for (name, tool) in [("objcopy", Tool::Objcopy), ("size", Tool::Size)] {
+            let program = tool.program_for(device);
+            if program != tool.program() {
+                checks.push(tool_check(
+                    &format!("{name} ({device})"),
+                    &program,
+                    &["--version"],
+                    BINUTILS_FIX,
+                ));
+            }
+        }
+    }
+    checks.extend(target_checks());
+
+    let errors = checks.iter().filter(|c| c.status == Status::Error).count();
+    let warnings = checks
+        .iter()
+        .filter(|c| c.status == Status::Warning)
+        .count();
+
+    if args.json {
+        let report = Report {
+            cargo_ledger_version: env!("CARGO_PKG_VERSION"),
+            host: format!("{}-{}", env::consts::ARCH, env::consts::OS),
+            checks,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for check in &checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Error => "error",
+            };
+            println!("[{status:^7}] {}: {}", check.name, check.detail);
+            if let Some(fix) = &check.fix {
+                println!("          help: {fix}");
+            }
+        }
+        println!("{errors} error(s), {warnings} warning(s)");
+    }
+
+    if errors > 0 {
+        return Err(LedgerError::Other(format!(
+            "Found {errors} problem(s) in the environment"
+        )));
+    }
+    Ok(())
+}
+
+/// First line printed by `program args`, if it runs successfully.
+fn first_line(program: &OsStr, args: &[&str]) -> Option<String> {
+    let out = output(Command::new(program).args(args)).ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    stdout
+        .lines()
+        .find(|l| !l.trim().is_empty())
+        .map(|l| l.trim().to_string())
+}
+
+fn tool_check(
+    name: &str,
+    program: &OsStr,
+    version_args: &[&str],
+    fix: &str,
+) -> Check {
+    let Some(path) = which(program) else {
+        return Check::error(
+            name,
+            format!("`{}` not found in PATH", program.to_string_lossy()),
+            fix,
+        );
+    };
+    match first_line(program, version_args) {
+        Some(version) => {
+            Check::ok(name, format!("{version} ({})", path.display()))
+        }
+        None => Check::error(
+            name,
+            format!("`{} {}` failed", path.display(), version_args.join(" ")),
+            fix,
+        ),
+    }
+}
+
+fn rustc_check() -> Check {
+    let fix = RUSTUP_FIX;
+    let Some(path) = which(OsStr::new("rustc")) else {
+        return Check::error("rustc", "`rustc` not found in PATH", fix);
+    };
+    let Ok(version) = rustc_output(None, &["--version"]) else {
+        return Check::error("rustc", "`rustc --version` failed", fix);
+    };
+    let detail = format!("{version} ({})", path.display());
+    if version.contains("nightly") || version.contains("dev") {
+        Check::ok("rustc", detail)
+    } else {
+        Check::warning(
+            "rustc",
+            detail,
+            "custom targets require a nightly toolchain, pin one in the \
+             project's rust-toolchain.toml",
+        )
+    }
+}
+
+/// `build-std` builds `core` from the `rust-src` component.
+fn rust_src_check() -> Check {
+    let Ok(rustlib) = rustlib_dir(None) else {
+        return Check::error(
+            "rust-src",
+            "could not locate the sysroot",
+            "make sure `rustc --print sysroot` works",
+        );
+    };
+    let library = rustlib.join("src").join("rust").join("library");
+    if library.is_dir() {
+        Check::ok("rust-src", library.display().to_string())
+    } else {
+        Check::error(
+            "rust-src",
+            format!("{} not found", library.display()),
+            "rustup component add rust-src",
+        )
+    }
+}
+
+fn ledgerblue_check() -> Check {
+    let version = match ledgerblue_version() {
+        Ok(version) => version,
+        Err(e) => {
+            return Check::error(
+                "ledgerblue",
+                e.to_string().lines().next().unwrap_or_default(),
+                PYTHON_FIX,
+            );
+        }
+    };
+    match check_ledgerblue_version(version.as_deref()) {
+        Err(LedgerError::UnsupportedTool { message, hint }) => {
+            Check::error("ledgerblue", message, hint)
+        }
+        _ => Check::ok("ledgerblue", version.unwrap_or_default()),
+    }
+}
+
+/// Custom targets, link script and setup manifest used by builds: the
+/// project-local ones if any, else those of the current toolchain.
+fn target_checks() -> Vec<Check> {
+    let (dir, local) = match project_targets_dir() {
+        Ok(dir) if dir.is_dir() => (dir, true),
+        _ => match rustlib_dir(None) {
+            Ok(dir) => (dir, false),
+            Err(e) => {
+                return vec![Check::error(
+                    "targets",
+                    format!("could not locate the sysroot: {e}"),
+                    "make sure `rustc --print sysroot` works",
+                )];
+            }
+        },
+    };
+    let setup = if local {
+        "cargo ledger setup --local"
+    } else {
+        "cargo ledger setup"
+    };
+
+    let mut checks = vec![];
+    let mut installed = 0;
+    for &device in Device::value_variants() {
+        let name = format!("{device} target");
+        let path = if local {
+            project_target_path(&dir, device)
+        } else {
+            sysroot_target_path(&dir, device)
+        };
+        if !path.is_file() {
+            checks.push(Check::warning(
+                name,
+                format!("{} not found", path.display()),
+                format!("{setup} --device {device}"),
+            ));
+            continue;
+        }
+        installed += 1;
+        checks.push(match check_target_spec(None, &path) {
+            Ok(SpecCheck::Valid) => Check::ok(name, path.display().to_string()),
+            Ok(SpecCheck::Unsupported) => Check::ok(
+                name,
+                format!("{} (not validated without nightly)", path.display()),
+            ),
+            Ok(SpecCheck::Invalid(reason)) => Check::error(
+                name,
+                format!("{} is rejected by rustc: {reason}", path.display()),
+                target_fix_hint(&dir),
+            ),
+            Err(e) => Check::error(
+                name,
+                format!("could not validate {}: {e}", path.display()),
+                "make sure `rustc` works",
+            ),
+        });
+    }
+    if installed == 0 {
+        checks.push(Check::error(
+            "targets",
+            format!("no custom target installed in {}", dir.display()),
+            setup,
+        ));
+    }
+
+    let link_scripts = if local {
+        Ok(vec![dir.join(LINK_SCRIPT)])
+    } else {
+        rust_lld_dirs(None, &dir)
+            .map(|dirs| dirs.iter().map(|d| d.join(LINK_SCRIPT)).collect())
+    };
+    match link_scripts {
+        Ok(paths) => {
+            checks.extend(paths.iter().map(|p| link_script_check(p, setup)))
+        }
+        Err(e) => checks.push(Check::error(
+            "rust-lld",
+            e.to_string(),
+            "rustup component add rustc, or reinstall the toolchain",
+        )),
+    }
+
+    checks.push(manifest_check(&dir, setup));
+    checks
+}
+
+fn link_script_check(path: &Path, setup: &str) -> Check {
+    let name = LINK_SCRIPT;
+    let Ok(metadata) = path.metadata() else {
+        return Check::error(
+            name,
+            format!("{} not found", path.display()),
+            setup,
+        );
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Check::error(
+                name,
+                format!("{} is not executable", path.display()),
+                format!("chmod +x {}", path.display()),
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    Check::ok(name, path.display().to_string())
+}
+
+fn manifest_check(dir: &Path, setup: &str) -> Check {
+    let name = "setup manifest";
+    let manifest = match SetupManifest::load(dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            return Check::warning(
+                name,
+                format!("{} not found", SetupManifest::path(dir).display()),
+                format!("{setup}, to record the installed files"),
+            );
+        }
+        Err(e) => {
+            // The error names the manifest
+            return Check::error(
+                name,
+                e.to_string(),
+                format!(
+                    "{setup} --force, to replace it (installed files differing \
+                     from the SDK ones are first saved with a .orig extension)"
+                ),
+            );
+        }
+    };
+
+    let sdk = manifest.sdk_version.as_deref().unwrap_or("unknown SDK");
+    let detail = format!(
+        "{sdk} from {}, installed with {}",
+        manifest.source, manifest.rustc_version
+    );
+    let modified: Vec<String> = manifest
+        .files
+        .iter()
+        .filter(|f| {
+            std::fs::read(&f.path)
+                .is_ok_and(|content| sha256_hex(&content) != f.sha256)
+        })
+        .map(|f| f.path.display().to_string())
+        .collect();
+    if !modified.is_empty() {
+        return Check::warning(
+            name,
+            format!("{detail}; locally modified: {}", modified.join(", ")),
+            format!("{setup} --force, to restore the SDK files"),
+        );
+    }
+    match (&manifest.sdk_version, project_sdk_version(OFFLINE)) {
+        (Some(installed), Ok(project)) if *installed != project => {
+            Check::warning(
+                name,
+                format!("{detail}; the project uses {project}"),
+                setup,
+            )
+        }
+        _ => Check::ok(name, detail),
+    }
+}
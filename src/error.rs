@@ -1,41 +1,161 @@
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
+use std::process::Command;
+
+use std::time::Duration;
+
+use crate::process::{CommandLine, Step, stderr_tail};
+use crate::runner::Tool;
 
 #[derive(Debug)]
 pub enum LedgerError {
     Io(std::io::Error),
     Metadata(cargo_metadata::Error),
     Goblin(goblin::error::Error),
+    Dwarf(gimli::Error),
+    Json(serde_json::Error),
     Utf8(std::str::Utf8Error),
+    Download {
+        url: String,
+        source: ureq::Error,
+    },
     CommandFailure {
-        cmd: &'static str,
+        cmd: Box<CommandLine>,
         status: Option<i32>,
+        /// Last lines of the command's stderr, if it was captured
         stderr: String,
     },
+    /// A step took longer than its time limit, its command was stopped
+    Timeout {
+        cmd: Box<CommandLine>,
+        step: Step,
+        after: Duration,
+    },
+    /// Ctrl-C or SIGTERM was caught, running commands were stopped
+    Interrupted,
+    /// A program could not be started because it is not installed
+    ToolNotFound(String),
+    /// Custom targets are missing or unusable, with how to fix them
+    SetupRequired {
+        message: String,
+        hint: String,
+    },
+    /// A tool is not installed or too old, with how to fix it
+    UnsupportedTool {
+        message: String,
+        hint: String,
+    },
+    /// The ELF does not look like a Ledger app
+    InvalidElf(String),
     MissingPackage,
     MissingField(&'static str),
     Other(String),
 }
 
+/// Category of an error, deciding the exit code of cargo-ledger. The codes
+/// are documented in the README and must not change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    Other,
+    MissingTool,
+    SetupRequired,
+    BuildFailure,
+    InvalidElf,
+    LedgerblueFailure,
+    TransportFailure,
+    Timeout,
+    Interrupted,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Other => 1,
+            // 2 is used by clap for usage errors
+            ErrorCategory::MissingTool => 3,
+            ErrorCategory::SetupRequired => 4,
+            ErrorCategory::BuildFailure => 5,
+            ErrorCategory::InvalidElf => 6,
+            ErrorCategory::LedgerblueFailure => 7,
+            ErrorCategory::TransportFailure => 8,
+            ErrorCategory::Timeout => 9,
+            // Conventional code of processes stopped by SIGINT
+            ErrorCategory::Interrupted => 130,
+        }
+    }
+}
+
+/// How to install Rust, shared by error hints and doctor fixes.
+pub const RUSTUP_FIX: &str = "install Rust with rustup, see https://rustup.rs";
+/// How to get objcopy and size.
+pub const BINUTILS_FIX: &str = "install the Arm GNU toolchain (e.g. `apt \
+     install binutils-arm-none-eabi`) or `rustup component add llvm-tools`, \
+     or set CARGO_TARGET_<DEVICE>_OBJCOPY and CARGO_TARGET_<DEVICE>_SIZE";
+/// How to get the Python interpreter running ledgerblue.
+pub const PYTHON_FIX: &str =
+    "install Python 3, or set LEDGER_PYTHON to the path of its interpreter";
+
+/// ledgerblue errors caused by the communication with the device.
+const TRANSPORT_ERRORS: [&str; 4] = [
+    "No dongle found",
+    "CommException",
+    "HIDException",
+    "open failed",
+];
+
 impl Display for LedgerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             LedgerError::Io(e) => write!(f, "I/O error: {e}"),
             LedgerError::Metadata(e) => write!(f, "cargo metadata error: {e}"),
             LedgerError::Goblin(e) => write!(f, "ELF parse error: {e}"),
+            LedgerError::Dwarf(e) => write!(f, "DWARF parse error: {e}"),
+            LedgerError::Json(e) => write!(f, "JSON error: {e}"),
             LedgerError::Utf8(e) => write!(f, "UTF-8 error: {e}"),
+            LedgerError::Download { url, .. } => {
+                write!(f, "Failed to download {url}")
+            }
             LedgerError::CommandFailure {
                 cmd,
                 status,
                 stderr,
             } => {
-                write!(
-                    f,
-                    "Command '{cmd}' failed (status: {:?}): {}",
-                    status,
-                    stderr.trim()
-                )
+                match status {
+                    Some(code) => write!(
+                        f,
+                        "`{}` failed with exit status {code}",
+                        cmd.name()
+                    )?,
+                    None => write!(
+                        f,
+                        "`{}` was terminated by a signal",
+                        cmd.name()
+                    )?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, "\n  stderr:")?;
+                    for line in stderr.lines() {
+                        write!(f, "\n    {line}")?;
+                    }
+                }
+                write!(f, "\n  reproduce with:\n    {cmd}")
+            }
+            LedgerError::Timeout { cmd, after, .. } => write!(
+                f,
+                "`{}` timed out after {}s\n  reproduce with:\n    {cmd}",
+                cmd.name(),
+                after.as_secs()
+            ),
+            LedgerError::Interrupted => write!(f, "Interrupted"),
+            LedgerError::ToolNotFound(program) => {
+                write!(f, "Could not run `{program}`: not found in PATH")
             }
+            LedgerError::SetupRequired { message, .. }
+            | LedgerError::UnsupportedTool { message, .. } => {
+                write!(f, "{message}")
+            }
+            LedgerError::InvalidElf(s) => write!(f, "Invalid app ELF: {s}"),
             LedgerError::MissingPackage => {
                 write!(f, "No package found in metadata result")
             }
@@ -45,13 +165,122 @@ impl Display for LedgerError {
     }
 }
 
+impl LedgerError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            LedgerError::ToolNotFound(_)
+            | LedgerError::UnsupportedTool { .. } => ErrorCategory::MissingTool,
+            LedgerError::Timeout { .. } => ErrorCategory::Timeout,
+            LedgerError::Interrupted => ErrorCategory::Interrupted,
+            LedgerError::SetupRequired { .. } => ErrorCategory::SetupRequired,
+            LedgerError::InvalidElf(_) | LedgerError::Goblin(_) => {
+                ErrorCategory::InvalidElf
+            }
+            LedgerError::CommandFailure { cmd, stderr, .. } => {
+                if cmd.args.iter().any(|a| a.starts_with("ledgerblue")) {
+                    if stderr.contains("No module named") {
+                        ErrorCategory::MissingTool
+                    } else if TRANSPORT_ERRORS
+                        .iter()
+                        .any(|e| stderr.contains(e))
+                    {
+                        ErrorCategory::TransportFailure
+                    } else {
+                        ErrorCategory::LedgerblueFailure
+                    }
+                } else if cmd.name() == "cargo build" {
+                    ErrorCategory::BuildFailure
+                } else {
+                    ErrorCategory::Other
+                }
+            }
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    /// Suggestion printed after the error to fix it.
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            LedgerError::ToolNotFound(program)
+                if OsStr::new(program) == Tool::Python.program() =>
+            {
+                Some(format!("{PYTHON_FIX} (currently `{program}`)"))
+            }
+            LedgerError::ToolNotFound(program) => {
+                let name = std::path::Path::new(program)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                Some(match name.as_str() {
+                    "cargo" | "rustc" | "rustup" => RUSTUP_FIX.into(),
+                    "speculos" => "pip install speculos".into(),
+                    n if n.starts_with("arm-none-eabi-") => BINUTILS_FIX.into(),
+                    _ => format!(
+                        "install `{program}`, or run `cargo ledger doctor` to \
+                         check the environment"
+                    ),
+                })
+            }
+            LedgerError::SetupRequired { hint, .. }
+            | LedgerError::UnsupportedTool { hint, .. } => Some(hint.clone()),
+            LedgerError::Timeout { step, .. } => Some(format!(
+                "raise the time limit with `--timeout {step}=<seconds>`, or \
+                 disable it with `--timeout {step}=0`"
+            )),
+            LedgerError::InvalidElf(_) | LedgerError::Goblin(_) => Some(
+                "make sure the app is built with the Ledger device SDK, which \
+                 provides the `ledger.*` sections"
+                    .into(),
+            ),
+            LedgerError::CommandFailure { .. } => match self.category() {
+                ErrorCategory::MissingTool => {
+                    Some(format!("{} -m pip install ledgerblue", python()))
+                }
+                ErrorCategory::TransportFailure => Some(
+                    "connect and unlock the device, and close any other \
+                     application using it (e.g. Ledger Live)"
+                        .into(),
+                ),
+                ErrorCategory::LedgerblueFailure => Some(format!(
+                    "{} -m pip install --upgrade ledgerblue, or run `cargo \
+                     ledger doctor` to check the environment",
+                    python()
+                )),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Failure of `cmd`, with the tail of its captured `stderr`.
+    pub fn command_failure(
+        cmd: &Command,
+        status: Option<i32>,
+        stderr: &[u8],
+    ) -> Self {
+        Self::CommandFailure {
+            cmd: Box::new(cmd.into()),
+            status,
+            stderr: stderr_tail(stderr),
+        }
+    }
+}
+
+/// Interpreter running ledgerblue, as shown in hints.
+fn python() -> String {
+    Tool::Python.program().to_string_lossy().into_owned()
+}
+
 impl Error for LedgerError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             LedgerError::Io(e) => Some(e),
             LedgerError::Metadata(e) => Some(e),
             LedgerError::Goblin(e) => Some(e),
+            LedgerError::Dwarf(e) => Some(e),
+            LedgerError::Json(e) => Some(e),
             LedgerError::Utf8(e) => Some(e),
+            LedgerError::Download { source, .. } => Some(source),
             _ => None,
         }
     }
@@ -72,8 +301,62 @@ impl From<goblin::error::Error> for LedgerError {
         Self::Goblin(value)
     }
 }
+impl From<gimli::Error> for LedgerError {
+    fn from(value: gimli::Error) -> Self {
+        Self::Dwarf(value)
+    }
+}
+impl From<serde_json::Error> for LedgerError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
 impl From<std::str::Utf8Error> for LedgerError {
     fn from(value: std::str::Utf8Error) -> Self {
         Self::Utf8(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(program: &str, args: &[&str], stderr: &str) -> LedgerError {
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        LedgerError::CommandFailure {
+            cmd: Box::new(CommandLine::from(&cmd)),
+            status: Some(1),
+            stderr: stderr.into(),
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        let code = |e: LedgerError| e.category().exit_code();
+        let load = ["-m", "ledgerblue.runScript", "--fileName", "app.apdu"];
+
+        let e = failure(
+            "python3",
+            &load,
+            "ledgerblue.commException: No dongle found",
+        );
+        assert_eq!(code(e), 8);
+        let e = failure("python3", &load, "No module named 'ledgerblue'");
+        assert_eq!(code(e), 3);
+        let e = failure("python3", &load, "ValueError: invalid APDU");
+        assert_eq!(code(e), 7);
+        let e = failure("cargo", &["build", "--release"], "error[E0308]");
+        assert_eq!(code(e), 5);
+        let e = failure("cargo", &["metadata"], "");
+        assert_eq!(code(e), 1);
+
+        let e = LedgerError::Timeout {
+            cmd: Box::new(CommandLine::from(&Command::new("cargo"))),
+            step: Step::Build,
+            after: Duration::from_secs(600),
+        };
+        assert_eq!(code(e), 9);
+        assert_eq!(code(LedgerError::Interrupted), 130);
+    }
+}
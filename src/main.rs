@@ -2,19 +2,41 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::process::Command;
-use std::process::Stdio;
 
 use cargo_metadata::Message;
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, Subcommand, ValueEnum};
 
 mod error;
+mod manifest;
+mod output;
+mod process;
+mod runner;
 use crate::error::LedgerError;
+use crate::output::{ColorChoice, Verbosity, info, verbose, warning};
+use crate::process::{
+    CommandLine, Step, StepTimeout, install_interrupt_handler, stream,
+};
+use crate::runner::{Tool, ToolPath, metadata};
 
-use setup::install_targets;
+use debug::debug_app;
+use doctor::{DoctorArgs, doctor};
+use ragger::{RaggerArgs, run_functional_tests};
+use setup::{
+    BuildTargets, LOCKED, OFFLINE, SetupArgs, SetupCommand,
+    check_installed_sdk_version, disable_network, install_targets,
+    uninstall_targets, validate_build_target,
+};
+use speculos::{SpeculosArgs, run_speculos, run_test_binary, run_unit_tests};
+use symbolize::{addr2line, parse_address};
 use utils::*;
 
+mod debug;
+mod doctor;
+mod ragger;
 mod setup;
+mod speculos;
+mod symbolize;
 mod utils;
 
 #[derive(Parser, Debug)]
@@ -31,6 +53,27 @@ enum Cli {
 
 #[derive(clap::Args, Debug)]
 struct CliArgs {
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    #[clap(help = "print more details, such as the commands run")]
+    verbose: u8,
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    #[clap(help = "only print warnings, errors and results")]
+    quiet: bool,
+    #[clap(long, value_enum, default_value = "auto", global = true)]
+    #[clap(help = "when to use colors (NO_COLOR is honored with auto)")]
+    color: ColorChoice,
+    #[clap(long, global = true, value_name = "STEP=SECONDS")]
+    #[clap(env = "CARGO_LEDGER_TIMEOUT", value_delimiter = ',')]
+    #[clap(
+        help = "time limit of a step (build, export, package or load), 0 to disable it"
+    )]
+    timeout: Vec<StepTimeout>,
+    #[clap(long, global = true, value_name = "TOOL=PATH")]
+    #[clap(env = "CARGO_LEDGER_TOOLS", value_delimiter = ',')]
+    #[clap(
+        help = "path of a tool (cargo, objcopy, size, python or speculos) to use instead of the default one"
+    )]
+    tool: Vec<ToolPath>,
     #[clap(subcommand)]
     command: MainCommand,
 }
@@ -63,13 +106,28 @@ impl AsRef<str> for Device {
     }
 }
 
+impl Device {
+    /// Model name used by Speculos for this device.
+    fn speculos_model(&self) -> &'static str {
+        match self {
+            Device::Nanox => "nanox",
+            Device::Nanosplus => "nanosp",
+            Device::Stax => "stax",
+            Device::Flex => "flex",
+            Device::ApexP => "apex_p",
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum MainCommand {
     #[clap(about = "install custom target files")]
+    #[clap(args_conflicts_with_subcommands = true)]
     Setup {
-        #[clap(short, long)]
-        #[clap(help = "git tag or branch to use")]
-        tag: Option<String>,
+        #[clap(subcommand)]
+        command: Option<SetupCommand>,
+        #[clap(flatten)]
+        args: SetupArgs,
     },
     #[clap(about = "build the project for a given device")]
     Build {
@@ -79,97 +137,437 @@ enum MainCommand {
         #[clap(short, long)]
         #[clap(help = "load on a device")]
         load: bool,
+        #[clap(long)]
+        #[clap(
+            help = "print the commands that would be run and the files written, without doing it"
+        )]
+        dry_run: bool,
+        #[clap(last = true)]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "build the project and run it in Speculos")]
+    Run {
+        #[clap(value_enum)]
+        #[clap(help = "device to emulate")]
+        device: Device,
+        #[clap(flatten)]
+        speculos: SpeculosArgs,
+        #[clap(last = true)]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "build the project and run the Ragger functional tests")]
+    Test {
+        #[clap(value_enum, required = true)]
+        #[clap(help = "devices to test on")]
+        devices: Vec<Device>,
+        #[clap(flatten)]
+        ragger: RaggerArgs,
         #[clap(last = true)]
         remaining_args: Vec<String>,
     },
+    #[clap(about = "run cargo test for a given device in Speculos")]
+    UnitTest {
+        #[clap(value_enum)]
+        #[clap(help = "device to test on")]
+        device: Device,
+        #[clap(flatten)]
+        speculos: SpeculosArgs,
+        #[clap(last = true)]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "build the project and debug it in Speculos with GDB")]
+    Debug {
+        #[clap(value_enum)]
+        #[clap(help = "device to emulate")]
+        device: Device,
+        #[clap(flatten)]
+        speculos: SpeculosArgs,
+        #[clap(last = true)]
+        remaining_args: Vec<String>,
+    },
+    #[clap(about = "check the tools and files needed to build apps")]
+    Doctor {
+        #[clap(flatten)]
+        args: DoctorArgs,
+    },
+    #[clap(about = "print the function, file and line of app addresses")]
+    Addr2line {
+        #[clap(help = "ELF of the app")]
+        elf: Utf8PathBuf,
+        #[clap(value_parser = parse_address)]
+        #[clap(required_unless_present = "log")]
+        #[clap(help = "addresses to symbolize")]
+        addrs: Vec<u64>,
+        #[clap(long, value_parser = parse_address)]
+        #[clap(num_args = 0..=1, default_missing_value = "0x40000000")]
+        #[clap(
+            help = "address the app was loaded at (Speculos load address if no value is given)"
+        )]
+        load_base: Option<u64>,
+        #[clap(long)]
+        #[clap(
+            help = "annotate the addresses found in a log file (- for stdin)"
+        )]
+        log: Option<Utf8PathBuf>,
+    },
+    #[clap(about = "cargo runner executing a test binary in Speculos")]
+    #[clap(hide = true)]
+    Runner {
+        #[clap(value_enum)]
+        device: Device,
+        #[clap(flatten)]
+        speculos: SpeculosArgs,
+        binary: Utf8PathBuf,
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        test_args: Vec<String>,
+    },
 }
 
 fn main() {
     if let Err(e) = entrypoint() {
-        eprintln!("Error: {e}");
+        eprintln!("{}: {e}", output::error_label("Error"));
         // Show source chain if any
         let mut src = e.source();
         while let Some(cause) = src {
             eprintln!("  caused by: {cause}");
             src = cause.source();
         }
-        std::process::exit(1);
+        if let Some(hint) = e.hint() {
+            eprintln!("{}: {hint}", output::warning_label("help"));
+        }
+        std::process::exit(e.category().exit_code());
     }
 }
 
 fn entrypoint() -> Result<(), LedgerError> {
     let Cli::Ledger(cli) = Cli::parse();
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, _) => Verbosity::Verbose,
+    };
+    output::init(verbosity, cli.color);
+    process::init(cli.timeout);
+    runner::init(cli.tool)?;
+    install_interrupt_handler()?;
     match cli.command {
-        MainCommand::Setup { tag: t } => {
-            install_targets(t)?;
+        MainCommand::Setup {
+            command: Some(SetupCommand::Uninstall { location, force }),
+            ..
+        } => {
+            uninstall_targets(&location, force)?;
+        }
+        MainCommand::Setup {
+            command: None,
+            args: s,
+        } => {
+            install_targets(&s)?;
         }
         MainCommand::Build {
             device: d,
             load: a,
+            dry_run,
+            remaining_args: r,
+        } => {
+            if dry_run {
+                plan_build(d, a, &r)?;
+            } else {
+                build_app(d, a, r)?;
+            }
+        }
+        MainCommand::Run {
+            device: d,
+            speculos: s,
+            remaining_args: r,
+        } => {
+            let app = build_app(d, false, r)?;
+            run_speculos(d, &app.elf_path, &app.infos.api_level, &s, false)?;
+        }
+        MainCommand::Debug {
+            device: d,
+            speculos: s,
+            remaining_args: r,
+        } => {
+            let app = build_app(d, false, r)?;
+            debug_app(d, &app.elf_path, &app.infos.api_level, &s)?;
+        }
+        MainCommand::Test {
+            devices: d,
+            ragger: t,
+            remaining_args: r,
+        } => {
+            run_functional_tests(&d, &t, &r)?;
+        }
+        MainCommand::UnitTest {
+            device: d,
+            speculos: s,
             remaining_args: r,
         } => {
-            build_app(d, a, r)?;
+            run_unit_tests(d, &s, &r)?;
+        }
+        MainCommand::Doctor { args } => {
+            doctor(&args)?;
+        }
+        MainCommand::Addr2line {
+            elf: e,
+            addrs: a,
+            load_base: b,
+            log: l,
+        } => {
+            addr2line(&e, &a, b, l.as_deref())?;
+        }
+        MainCommand::Runner {
+            device: d,
+            speculos: s,
+            binary: b,
+            test_args: t,
+        } => {
+            // The test binary runs in Speculos, which cannot pass it
+            // arguments
+            if !t.is_empty() {
+                warning!(
+                    "test arguments are not supported in Speculos, ignoring \
+                     `{}`: every test is run",
+                    t.join(" ")
+                );
+            }
+            let code = run_test_binary(d, &b, &s)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
         }
     }
     Ok(())
 }
 
-fn build_app(
+/// Stay off the network, e.g. in hints, when cargo is asked to.
+fn follow_cargo_offline(remaining_args: &[String]) {
+    if remaining_args
+        .iter()
+        .any(|a| a == "--offline" || a == "--frozen")
+    {
+        disable_network();
+    }
+}
+
+/// `cargo build` command building the app for `device`.
+fn cargo_build_command(
+    device: Device,
+    targets: &BuildTargets,
+    remaining_args: &[String],
+) -> Result<Command, LedgerError> {
+    let message_format = if output::color_stdout() {
+        "json-diagnostic-rendered-ansi"
+    } else {
+        "json"
+    };
+    let mut args: Vec<String> = vec![
+        String::from("build"),
+        String::from("--release"),
+        format!("--target={}", device.as_ref()),
+        format!("--message-format={message_format}"),
+    ];
+    args.extend(output::cargo_args());
+
+    let mut cargo_cmd = Tool::Cargo.command();
+    targets.apply(&mut cargo_cmd)?;
+    cargo_cmd.args(args).args(remaining_args);
+    Ok(cargo_cmd)
+}
+
+/// Arguments given to ledgerblue to package the app.
+fn ledgerblue_params(
+    infos: &LedgerAppInfos,
+    hex_path: &Utf8PathBuf,
+) -> HashMap<String, String> {
+    let mut lb_params: HashMap<String, String> = HashMap::new();
+    lb_params.insert("name".to_string(), infos.app_name.clone());
+    lb_params.insert("targetId".to_string(), infos.target_id.clone());
+    lb_params.insert("apiLevel".to_string(), infos.api_level.clone());
+    lb_params.insert("flags".to_string(), infos.app_flags.clone());
+    lb_params.insert("binary".to_string(), hex_path.clone().into_string());
+    lb_params.insert("dataSize".to_string(), infos.data_size.to_string());
+    lb_params.insert(
+        "installParamsSize".to_string(),
+        infos.install_params_size.to_string(),
+    );
+    lb_params
+}
+
+/// Directory given to cargo with `--artifact-dir=<dir>`, where the package
+/// files are copied too.
+fn artifact_dir(remaining_args: &[String]) -> Option<Utf8PathBuf> {
+    remaining_args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--artifact-dir="))
+        .map(Utf8PathBuf::from)
+}
+
+/// Files copied to the artifact directory.
+fn artifact_files(
+    hex_path: &Utf8Path,
+    apdu_path: &Utf8Path,
+) -> [Utf8PathBuf; 3] {
+    [
+        hex_path.to_path_buf(),
+        apdu_path.to_path_buf(),
+        apdu_path.with_extension("sha256"),
+    ]
+}
+
+/// Print the commands `build_app` would run and the files it would write,
+/// without doing it. The ledgerblue arguments read from the ELF are taken
+/// from a previous build, if any.
+fn plan_build(
     device: Device,
     is_load: bool,
-    remaining_args: Vec<String>,
+    remaining_args: &[String],
 ) -> Result<(), LedgerError> {
-    let elf_path = {
-        let mut args: Vec<String> = vec![];
+    follow_cargo_offline(remaining_args);
+    let project = metadata(cargo_metadata::MetadataCommand::new().no_deps())?;
+    let targets = BuildTargets::locate(&project)?;
+    check_installed_sdk_version(&targets, LOCKED);
+    match validate_build_target(&targets, device) {
+        // Not fatal, setup may not have been run yet on this machine
+        Err(LedgerError::SetupRequired { message, hint }) => {
+            warning!("{message}: {hint} before building");
+        }
+        res => res?,
+    }
 
-        args.push(String::from("build"));
-        args.push(String::from("--release"));
-        args.push(format!("--target={}", device.as_ref()));
-        args.push(String::from(
-            "--message-format=json-diagnostic-rendered-ansi",
-        ));
+    let cargo_cmd = cargo_build_command(device, &targets, remaining_args)?;
+    output::planned("run", CommandLine::from(&cargo_cmd));
 
-        let mut cargo_cmd = Command::new("cargo")
-            .args(args)
-            .args(&remaining_args)
-            .stdout(Stdio::piped())
-            .spawn()?;
+    let this_pkg =
+        project.packages.last().ok_or(LedgerError::MissingPackage)?;
+    let package_path = this_pkg
+        .manifest_path
+        .parent()
+        .ok_or(LedgerError::MissingField("package parent path"))?;
+    let bin = this_pkg
+        .targets
+        .iter()
+        .find(|t| t.is_bin())
+        .ok_or(LedgerError::MissingField("binary target"))?;
+    let elf_path = project
+        .target_directory
+        .join(device.as_ref())
+        .join("release")
+        .join(&bin.name);
 
+    let hex_path = elf_path.with_extension("hex");
+    output::planned(
+        "run",
+        CommandLine::from(&objcopy_command(device, &elf_path, &hex_path)),
+    );
+    output::planned("run", CommandLine::from(&size_command(device, &elf_path)));
+
+    let lb_params = if elf_path.is_file() {
+        ledgerblue_params(&retrieve_infos(&elf_path)?, &hex_path)
+    } else {
+        info!(
+            "{elf_path} is not built yet, values read from it are shown as placeholders"
+        );
+        let mut params =
+            ledgerblue_params(&LedgerAppInfos::default(), &hex_path);
+        for (key, placeholder) in [
+            ("name", "<ledger.app_name>"),
+            ("targetId", "<ledger.target_id>"),
+            ("apiLevel", "<ledger.api_level>"),
+            ("flags", "<ledger.app_flags>"),
+            ("dataSize", "<data size>"),
+            ("installParamsSize", "<install parameters size>"),
+        ] {
+            params.insert(key.into(), placeholder.into());
+        }
+        params
+    };
+    let apdu_path = elf_path.with_extension("apdu");
+    output::planned(
+        "run",
+        CommandLine::from(&ledgerblue_dump_command(
+            package_path,
+            &lb_params,
+            &apdu_path,
+        )),
+    );
+    if is_load {
+        output::planned(
+            "run",
+            CommandLine::from(&ledgerblue_install_command(
+                package_path,
+                &lb_params,
+                &apdu_path,
+            )),
+        );
+    }
+
+    let files = artifact_files(&hex_path, &apdu_path);
+    for path in &files {
+        output::planned("write", path);
+    }
+    if let Some(out_dir) = artifact_dir(remaining_args) {
+        for path in &files {
+            let dest = out_dir.join(path.file_name().unwrap());
+            output::planned("copy", format!("{path} -> {dest}"));
+        }
+    }
+    Ok(())
+}
+
+/// Outputs of a successful `build_app`.
+struct BuiltApp {
+    package_path: Utf8PathBuf,
+    elf_path: Utf8PathBuf,
+    infos: LedgerAppInfos,
+}
+
+fn build_app(
+    device: Device,
+    is_load: bool,
+    remaining_args: Vec<String>,
+) -> Result<BuiltApp, LedgerError> {
+    follow_cargo_offline(&remaining_args);
+    let project = metadata(cargo_metadata::MetadataCommand::new().no_deps())?;
+    let targets = BuildTargets::locate(&project)?;
+    check_installed_sdk_version(&targets, OFFLINE);
+    validate_build_target(&targets, device)?;
+    // Fail before building rather than after
+    check_ledgerblue()?;
+
+    let elf_path = {
+        let mut cargo_cmd =
+            cargo_build_command(device, &targets, &remaining_args)?;
         let mut elf_path = Utf8PathBuf::new();
-        let out = cargo_cmd.stdout.take().ok_or_else(|| {
-            LedgerError::Other("Failed to take cargo stdout".into())
-        })?;
-        let reader = std::io::BufReader::new(out);
-        for message in Message::parse_stream(reader) {
-            match message.map_err(|e| {
-                LedgerError::Other(format!("Message stream error: {e}"))
-            })? {
-                Message::CompilerArtifact(artifact) => {
-                    if let Some(n) = &artifact.executable {
-                        elf_path = n.to_path_buf();
+        let out = stream(&mut cargo_cmd, Step::Build, &mut |line| {
+            // Diagnostics are printed from the JSON messages on stdout
+            for message in Message::parse_stream(line).flatten() {
+                match message {
+                    Message::CompilerArtifact(artifact) => {
+                        if let Some(n) = &artifact.executable {
+                            elf_path = n.to_path_buf();
+                        }
                     }
+                    Message::CompilerMessage(message) => {
+                        println!("{message}");
+                    }
+                    _ => {}
                 }
-                Message::CompilerMessage(message) => {
-                    println!("{message}");
-                }
-                _ => {}
             }
-        }
-        let status = cargo_cmd.wait()?;
-        if !status.success() {
-            return Err(LedgerError::CommandFailure {
-                cmd: "cargo build",
-                status: status.code(),
-                stderr: String::new(),
-            });
+        })?;
+        if !out.status.success() {
+            return Err(LedgerError::command_failure(
+                &cargo_cmd,
+                out.status.code(),
+                &out.stderr,
+            ));
         }
         elf_path
     };
 
     // Retrieve package path
-    let mut cmd = cargo_metadata::MetadataCommand::new();
-    let res = cmd.no_deps().exec()?;
-    let this_pkg = res.packages.last().ok_or(LedgerError::MissingPackage)?;
+    let this_pkg =
+        project.packages.last().ok_or(LedgerError::MissingPackage)?;
     let package_path = this_pkg
         .manifest_path
         .parent()
@@ -177,25 +575,22 @@ fn build_app(
 
     // Retrieve hex path and export binary
     let hex_path = elf_path.with_extension("hex");
-    println!("Exporting binary from ELF {} to {}", elf_path, hex_path);
-    export_binary(&elf_path, &hex_path)?;
+    info!("Exporting binary from ELF {} to {}", elf_path, hex_path);
+    export_binary(device, &elf_path, &hex_path)?;
 
     // Retrieve info from ELF
+    info!("Retrieving Ledger app infos from ELF: {}", elf_path);
     let infos = retrieve_infos(&elf_path)?;
-    println!("Retrieved ELF infos: {:?}", infos);
+    verbose!("Retrieved ELF infos: {:?}", infos);
+    // ledgerblue needs them to package the app
+    if infos.app_name.is_empty() || infos.target_id.is_empty() {
+        return Err(LedgerError::InvalidElf(format!(
+            "{elf_path} has no ledger.app_name or ledger.target_id section"
+        )));
+    }
 
     // Dump with ledgerblue and optionally install
-    let mut lb_params: HashMap<String, String> = HashMap::new();
-    lb_params.insert("name".to_string(), infos.app_name);
-    lb_params.insert("targetId".to_string(), infos.target_id);
-    lb_params.insert("apiLevel".to_string(), infos.api_level);
-    lb_params.insert("flags".to_string(), infos.app_flags);
-    lb_params.insert("binary".to_string(), hex_path.clone().into_string());
-    lb_params.insert("dataSize".to_string(), infos.data_size.to_string());
-    lb_params.insert(
-        "installParamsSize".to_string(),
-        infos.install_params_size.to_string(),
-    );
+    let lb_params = ledgerblue_params(&infos, &hex_path);
 
     let apdu_path = elf_path.with_extension("apdu");
     dump_with_ledgerblue(package_path, &lb_params, &apdu_path)?;
@@ -203,27 +598,23 @@ fn build_app(
         install_with_ledgerblue(package_path, &lb_params, &apdu_path)?;
     }
 
-    remaining_args
-        .iter()
-        .find(|arg| arg.starts_with("--artifact-dir="))
-        .and_then(|arg| {
-            let out_dir = arg.trim_start_matches("--artifact-dir=");
-            let out_path =
-                Utf8PathBuf::from(out_dir).join(hex_path.file_name().unwrap());
-            std::fs::copy(&hex_path, &out_path).ok()?;
-            let out_path =
-                Utf8PathBuf::from(out_dir).join(apdu_path.file_name().unwrap());
-            std::fs::copy(&apdu_path, &out_path).ok()?;
-            let sha256_path = apdu_path.with_extension("sha256");
-            if sha256_path.exists() {
-                let out_path = Utf8PathBuf::from(out_dir)
-                    .join(sha256_path.file_name().unwrap());
-                std::fs::copy(&sha256_path, &out_path).ok()?;
+    if let Some(out_dir) = artifact_dir(&remaining_args) {
+        for path in artifact_files(&hex_path, &apdu_path) {
+            let dest = out_dir.join(path.file_name().unwrap());
+            // The hash is only known when ledgerblue printed it
+            if path.exists()
+                && let Err(e) = std::fs::copy(&path, &dest)
+            {
+                warning!("could not copy {path} to {dest}: {e}");
             }
-            Some(())
-        });
+        }
+    }
 
-    Ok(())
+    Ok(BuiltApp {
+        package_path: package_path.to_path_buf(),
+        elf_path,
+        infos,
+    })
 }
 
 // #[cfg(test)]
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::LedgerError;
+use crate::output::warning;
+use crate::utils::write_atomic;
+
+/// Name of the manifest written by `setup` next to the installed targets.
+const MANIFEST_FILE: &str = "cargo-ledger-setup.json";
+
+/// Record of the files installed by `setup`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetupManifest {
+    /// URL or path the files were retrieved from
+    pub source: String,
+    /// SDK git tag or `<crate>@<version>` of the files, when known
+    pub sdk_version: Option<String>,
+    /// Output of `rustc --version` for the toolchain the files were
+    /// installed in
+    pub rustc_version: String,
+    /// Installation time, in seconds since the UNIX epoch
+    pub installed_at: u64,
+    pub files: Vec<InstalledFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstalledFile {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+impl SetupManifest {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST_FILE)
+    }
+
+    /// Load the manifest stored in `dir`, if any.
+    pub fn load(dir: &Path) -> Result<Option<Self>, LedgerError> {
+        let path = Self::path(dir);
+        match fs::read(&path) {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(manifest) => Ok(Some(manifest)),
+                Err(e) => Err(LedgerError::Other(format!(
+                    "Invalid setup manifest {}: {e}",
+                    path.display()
+                ))),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load the manifest stored in `dir` like `load`, but with `force`, an
+    /// unreadable manifest is reported and ignored rather than failing.
+    pub fn load_or_ignore(
+        dir: &Path,
+        force: bool,
+    ) -> Result<Option<Self>, LedgerError> {
+        match Self::load(dir) {
+            Err(e) if force => {
+                warning!("{e}, ignoring it");
+                Ok(None)
+            }
+            Err(e) => Err(LedgerError::Other(format!(
+                "{e}, use --force to ignore it"
+            ))),
+            res => res,
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), LedgerError> {
+        let content = serde_json::to_string_pretty(self)?;
+        write_atomic(&Self::path(dir), format!("{content}\n").as_bytes())
+    }
+
+    /// Hash recorded at installation time for `path`.
+    pub fn recorded_sha256(&self, path: &Path) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|f| f.path == path)
+            .map(|f| f.sha256.as_str())
+    }
+}
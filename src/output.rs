@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Apply the global output options. With `auto`, colors are used on
+/// terminals unless `NO_COLOR` is set to a non-empty value.
+pub fn init(verbosity: Verbosity, color: ColorChoice) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let (stdout, stderr) = match color {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto if no_color => (false, false),
+        ColorChoice::Auto => (
+            std::io::stdout().is_terminal(),
+            std::io::stderr().is_terminal(),
+        ),
+    };
+    COLOR_STDOUT.store(stdout, Ordering::Relaxed);
+    COLOR_STDERR.store(stderr, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Whether colors are used on stdout.
+pub fn color_stdout() -> bool {
+    COLOR_STDOUT.load(Ordering::Relaxed)
+}
+
+/// Whether colors are used on stderr.
+pub fn color_stderr() -> bool {
+    COLOR_STDERR.load(Ordering::Relaxed)
+}
+
+fn paint(s: impl Display, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{code}m{s}\x1b[0m")
+    } else {
+        s.to_string()
+    }
+}
+
+/// Emphasize a path or value printed on stdout.
+pub fn highlight(s: impl Display) -> String {
+    paint(s, "1;33", color_stdout())
+}
+
+/// Label of an error message printed on stderr.
+pub fn error_label(s: impl Display) -> String {
+    paint(s, "1;31", color_stderr())
+}
+
+/// Label of a warning or hint printed on stderr.
+pub fn warning_label(s: impl Display) -> String {
+    paint(s, "1;33", color_stderr())
+}
+
+/// Print a progress or informational line on stdout, unless `--quiet`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() > $crate::output::Verbosity::Quiet {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+/// Print a detail on stderr, only with `--verbose`.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() == $crate::output::Verbosity::Verbose {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use verbose;
+
+/// Print a warning on stderr.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        eprintln!(
+            "{}: {}",
+            $crate::output::warning_label("Warning"),
+            format_args!($($arg)*)
+        );
+    };
+}
+pub(crate) use warning;
+
+/// Print an action skipped by `--dry-run`, e.g. `Would run: <command>`.
+pub fn planned(action: &str, what: impl Display) {
+    println!("Would {action}: {what}");
+}
+
+/// Arguments passing the output options on to cargo, or to another
+/// cargo-ledger run which takes the same ones.
+pub fn cargo_args() -> Vec<String> {
+    let color = if color_stderr() { "always" } else { "never" };
+    let mut args = vec![format!("--color={color}")];
+    match verbosity() {
+        Verbosity::Quiet => args.push("--quiet".into()),
+        Verbosity::Normal => {}
+        Verbosity::Verbose => args.push("--verbose".into()),
+    }
+    args
+}
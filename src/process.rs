@@ -0,0 +1,549 @@
+use clap::ValueEnum;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::LedgerError;
+use crate::output::{Verbosity, verbose, verbosity};
+use crate::runner::runner;
+
+/// Number of stderr lines kept in command failures.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Time given to a child to exit after being asked to terminate, before it
+/// is killed.
+const TERMINATE_GRACE: Duration = Duration::from_secs(3);
+
+/// Step of the build pipeline, each with its own time limit.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// `cargo build`
+    Build,
+    /// `objcopy` and `size`
+    Export,
+    /// ledgerblue `loadApp`, generating the APDU file
+    Package,
+    /// ledgerblue `runScript`, loading the app on a device
+    Load,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Step::Build => "build",
+            Step::Export => "export",
+            Step::Package => "package",
+            Step::Load => "load",
+        })
+    }
+}
+
+impl Step {
+    /// Time limit when none is configured. Builds can legitimately take
+    /// long, loading leaves time to confirm on the device.
+    fn default_timeout(self) -> Option<Duration> {
+        match self {
+            Step::Build => None,
+            Step::Export => Some(Duration::from_secs(60)),
+            Step::Package => Some(Duration::from_secs(120)),
+            Step::Load => Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// `<step>=<seconds>` time limit given on the command line, 0 disabling it.
+#[derive(Clone, Copy, Debug)]
+pub struct StepTimeout {
+    step: Step,
+    timeout: Option<Duration>,
+}
+
+impl FromStr for StepTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (step, secs) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <step>=<seconds>, got `{s}`"))?;
+        let step = Step::from_str(step.trim(), true)?;
+        let secs: u64 = secs
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid number of seconds `{secs}`: {e}"))?;
+        Ok(Self {
+            step,
+            timeout: (secs > 0).then(|| Duration::from_secs(secs)),
+        })
+    }
+}
+
+static TIMEOUTS: OnceLock<Vec<StepTimeout>> = OnceLock::new();
+
+/// Apply the time limits given on the command line.
+pub fn init(timeouts: Vec<StepTimeout>) {
+    let _ = TIMEOUTS.set(timeouts);
+}
+
+/// Arguments passing the time limits given on the command line on to another
+/// cargo-ledger run.
+pub fn timeout_args() -> Vec<String> {
+    TIMEOUTS
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|t| {
+            let secs = t.timeout.map_or(0, |t| t.as_secs());
+            format!("--timeout={}={secs}", t.step)
+        })
+        .collect()
+}
+
+/// Time limit of `step`, the last one given for it winning.
+pub fn timeout(step: Step) -> Option<Duration> {
+    TIMEOUTS
+        .get()
+        .and_then(|t| t.iter().rev().find(|t| t.step == step))
+        .map_or(step.default_timeout(), |t| t.timeout)
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Number of children being run, which are in charge of handling an
+/// interruption.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Catch Ctrl-C and SIGTERM. While children run, they are asked to terminate
+/// and reaped before cargo-ledger exits; otherwise it exits right away.
+pub fn install_interrupt_handler() -> Result<(), LedgerError> {
+    let handler = || {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        if RUNNING.load(Ordering::SeqCst) == 0 {
+            std::process::exit(130);
+        }
+    };
+    match ctrlc::set_handler(handler) {
+        Ok(()) | Err(ctrlc::Error::MultipleHandlers) => Ok(()),
+        Err(e) => Err(LedgerError::Other(format!(
+            "Failed to install signal handler: {e}"
+        ))),
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail with `Interrupted` once an interruption was caught.
+pub fn check_interrupted() -> Result<(), LedgerError> {
+    if interrupted() {
+        return Err(LedgerError::Interrupted);
+    }
+    Ok(())
+}
+
+/// Send `signal` to the process group led by `child` (see `spawn_child`), so
+/// that the processes it started get it too, even once it exited.
+#[cfg(unix)]
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // SAFETY: a group keeps the id of its leader while any of its processes
+    // is alive, so only the child and the processes it started are reached.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
+
+/// Forward Ctrl-C, which the terminal does not send to the child as it runs
+/// in a process group of its own.
+#[cfg(unix)]
+fn interrupt(child: &mut Child) {
+    signal_group(child, libc::SIGINT);
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    signal_group(child, libc::SIGTERM);
+}
+
+/// Kill the child and the processes it started, which may outlive it.
+#[cfg(unix)]
+fn kill(child: &mut Child) -> io::Result<()> {
+    signal_group(child, libc::SIGKILL);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn interrupt(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// A spawned child, asked to terminate and reaped if dropped while it still
+/// runs, e.g. on an early error return.
+pub struct Running {
+    child: Child,
+    cmd: CommandLine,
+    reaped: bool,
+}
+
+impl Running {
+    fn new(child: Child, cmd: CommandLine) -> Self {
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        Self {
+            child,
+            cmd,
+            reaped: false,
+        }
+    }
+
+    /// Wait for the child to exit. Once an interruption is caught, Ctrl-C is
+    /// forwarded to it and it is killed if it is still alive after a grace
+    /// period, along with the processes it started. Past the time limit of
+    /// `step`, it is stopped the same way and an error is returned.
+    pub fn wait(
+        &mut self,
+        step: Option<Step>,
+    ) -> Result<ExitStatus, LedgerError> {
+        let limit = step.and_then(|s| Some((s, timeout(s)?)));
+        let start = Instant::now();
+        let mut deadline: Option<Instant> = None;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                self.reaped = true;
+                if deadline.is_some() {
+                    // e.g. background jobs of scripts, which ignore SIGINT
+                    let _ = kill(&mut self.child);
+                }
+                return Ok(status);
+            }
+            if deadline.is_none()
+                && !interrupted()
+                && let Some((step, limit)) = limit
+                && start.elapsed() >= limit
+            {
+                self.stop();
+                return Err(LedgerError::Timeout {
+                    cmd: Box::new(self.cmd.clone()),
+                    step,
+                    after: limit,
+                });
+            }
+            match deadline {
+                None if interrupted() => {
+                    interrupt(&mut self.child);
+                    deadline = Some(Instant::now() + TERMINATE_GRACE);
+                }
+                Some(d) if Instant::now() >= d => {
+                    kill(&mut self.child)?;
+                    self.reaped = true;
+                    return Ok(self.child.wait()?);
+                }
+                _ => {}
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Ask the child to terminate, kill it after the grace period, and reap
+    /// it. The processes it started are killed in any case.
+    fn stop(&mut self) {
+        terminate(&mut self.child);
+        let deadline = Instant::now() + TERMINATE_GRACE;
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                self.reaped = true;
+                let _ = kill(&mut self.child);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let _ = kill(&mut self.child);
+        let _ = self.child.wait();
+        self.reaped = true;
+    }
+}
+
+impl Deref for Running {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for Running {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if !self.reaped {
+            self.stop();
+        }
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Command line of a child process, kept to report and reproduce failures.
+#[derive(Debug, Clone)]
+pub struct CommandLine {
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// Environment variables set (or removed, `None`) for the child
+    pub envs: Vec<(String, Option<String>)>,
+}
+
+impl From<&Command> for CommandLine {
+    fn from(cmd: &Command) -> Self {
+        let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+        Self {
+            program: lossy(cmd.get_program()),
+            args: cmd.get_args().map(lossy).collect(),
+            cwd: cmd.get_current_dir().map(PathBuf::from),
+            envs: cmd
+                .get_envs()
+                .map(|(k, v)| (lossy(k), v.map(lossy)))
+                .collect(),
+        }
+    }
+}
+
+impl CommandLine {
+    /// Short name of the command, e.g. `cargo build`.
+    pub fn name(&self) -> String {
+        let program = std::path::Path::new(&self.program)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.program.clone());
+        match self.args.iter().find(|a| !a.starts_with('-')) {
+            Some(sub) if self.args.first() == Some(sub) => {
+                format!("{program} {sub}")
+            }
+            _ => program,
+        }
+    }
+}
+
+/// Error for `cmd` failing to start: a missing program is reported as such.
+pub fn spawn_error(cmd: &Command, e: io::Error) -> LedgerError {
+    if e.kind() == io::ErrorKind::NotFound {
+        LedgerError::ToolNotFound(cmd.get_program().to_string_lossy().into())
+    } else {
+        e.into()
+    }
+}
+
+/// `Command::output` through the runner, reporting missing programs.
+pub fn output(cmd: &mut Command) -> Result<Output, LedgerError> {
+    verbose!("Running `{}`", CommandLine::from(&*cmd));
+    runner().output(cmd)
+}
+
+/// `Command::spawn`, reporting missing programs. The child is reaped even
+/// if the result is dropped early.
+pub fn spawn(cmd: &mut Command) -> Result<Running, LedgerError> {
+    verbose!("Running `{}`", CommandLine::from(&*cmd));
+    spawn_child(cmd)
+}
+
+/// `spawn`, without printing the command. On Unix, the child leads a new
+/// process group, so that stopping it also stops the processes it started.
+pub fn spawn_child(cmd: &mut Command) -> Result<Running, LedgerError> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+    let child = cmd.spawn().map_err(|e| spawn_error(cmd, e))?;
+    Ok(Running::new(child, CommandLine::from(&*cmd)))
+}
+
+/// `Command::status`, reporting missing programs and interruptions.
+pub fn status(cmd: &mut Command) -> Result<ExitStatus, LedgerError> {
+    let status = spawn(cmd)?.wait(None)?;
+    check_interrupted()?;
+    Ok(status)
+}
+
+/// Quote `s` for a POSIX shell, if needed.
+fn shell_quote(s: &str) -> String {
+    let safe = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%^".contains(c));
+    if safe {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Shell command reproducing the command line.
+impl Display for CommandLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(cwd) = &self.cwd {
+            write!(f, "cd {} && ", shell_quote(&cwd.to_string_lossy()))?;
+        }
+        for (key, value) in &self.envs {
+            match value {
+                None => write!(f, "env -u {key} ")?,
+                Some(value) => {
+                    // Show prepended PATH entries rather than the whole PATH
+                    let current = env::var(key).ok().map(|v| format!(":{v}"));
+                    match current.and_then(|c| value.strip_suffix(&c)) {
+                        Some(prefix) => write!(
+                            f,
+                            "{key}={}:\"${key}\" ",
+                            shell_quote(prefix)
+                        )?,
+                        None => write!(f, "{key}={} ", shell_quote(value))?,
+                    }
+                }
+            }
+        }
+        write!(f, "{}", shell_quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", shell_quote(arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove ANSI escape sequences (colors) from `s`.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Last lines of a child's stderr, without colors.
+pub fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = strip_ansi(&String::from_utf8_lossy(stderr));
+    let lines: Vec<&str> = stderr.trim_end().lines().collect();
+    let start = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[start..].join("\n")
+}
+
+fn forward_stdout(line: &[u8]) {
+    if verbosity() > Verbosity::Quiet {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(line);
+        let _ = stdout.flush();
+    }
+}
+
+/// Run `cmd` as `step` through the runner, passing each line of its stdout
+/// to `on_stdout` and its stderr through while it runs, and return them once
+/// the command exits.
+pub fn stream(
+    cmd: &mut Command,
+    step: Step,
+    on_stdout: &mut (dyn FnMut(&[u8]) + Send),
+) -> Result<Output, LedgerError> {
+    verbose!("Running `{}`", CommandLine::from(&*cmd));
+    let out = runner().stream(cmd, step, on_stdout)?;
+    check_interrupted()?;
+    Ok(out)
+}
+
+/// Run `cmd` as `step`, passing its output through line by line while it
+/// runs (stdout being hidden by `--quiet`), and return it once the command
+/// exits.
+pub fn output_streamed(
+    cmd: &mut Command,
+    step: Step,
+) -> Result<Output, LedgerError> {
+    stream(cmd, step, &mut forward_stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_timeout() {
+        let t: StepTimeout = "export=30".parse().unwrap();
+        assert_eq!(t.step, Step::Export);
+        assert_eq!(t.timeout, Some(Duration::from_secs(30)));
+
+        let t: StepTimeout = " Build = 0 ".parse().unwrap();
+        assert_eq!(t.step, Step::Build);
+        assert_eq!(t.timeout, None);
+
+        assert!("export".parse::<StepTimeout>().is_err());
+        assert!("deploy=30".parse::<StepTimeout>().is_err());
+        assert!("load=-1".parse::<StepTimeout>().is_err());
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(shell_quote("--target=nanox"), "--target=nanox");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn command_line_display() {
+        let mut cmd = Command::new("python3");
+        cmd.current_dir("/my app")
+            .env("CARGO_LEDGER_TEST_UNSET_VAR", "1")
+            .env_remove("CARGO_LEDGER_TEST_REMOVED_VAR")
+            .args(["-m", "ledgerblue.loadApp", "--appName", "My App"]);
+        let cmd = CommandLine::from(&cmd);
+        assert_eq!(cmd.name(), "python3");
+        let shown = cmd.to_string();
+        assert!(shown.starts_with("cd '/my app' && "));
+        assert!(shown.contains("CARGO_LEDGER_TEST_UNSET_VAR=1 "));
+        assert!(shown.contains("env -u CARGO_LEDGER_TEST_REMOVED_VAR "));
+        assert!(
+            shown.ends_with("python3 -m ledgerblue.loadApp --appName 'My App'")
+        );
+
+        let mut cmd = Command::new("/usr/bin/cargo");
+        cmd.args(["build", "--release"]);
+        assert_eq!(CommandLine::from(&cmd).name(), "cargo build");
+        let mut cmd = Command::new("cargo");
+        cmd.args(["--config", "x", "test"]);
+        assert_eq!(CommandLine::from(&cmd).name(), "cargo");
+    }
+
+    #[test]
+    fn stderr_tail_strips_colors_and_keeps_last_lines() {
+        assert_eq!(
+            stderr_tail(b"\x1b[1;31merror\x1b[0m: failed\n\n"),
+            "error: failed"
+        );
+        let long: String = (0..30).map(|i| format!("line {i}\n")).collect();
+        let tail = stderr_tail(long.as_bytes());
+        assert_eq!(tail.lines().count(), STDERR_TAIL_LINES);
+        assert!(tail.starts_with("line 10\n"));
+        assert!(tail.ends_with("line 29"));
+    }
+}
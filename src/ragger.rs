@@ -0,0 +1,210 @@
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use std::fmt::Write as _;
+use std::fs;
+
+use crate::error::LedgerError;
+use crate::output::{Verbosity, color_stdout, info, verbosity};
+use crate::process::status;
+use crate::runner::Tool;
+use crate::{Device, build_app};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct RaggerArgs {
+    #[clap(long, default_value = "tests")]
+    #[clap(help = "directory containing the Ragger test suites")]
+    pub tests_dir: Utf8PathBuf,
+    #[clap(long)]
+    #[clap(help = "write a JUnit report aggregating all devices")]
+    pub junit: Option<Utf8PathBuf>,
+    #[clap(long = "pytest-arg", allow_hyphen_values = true)]
+    #[clap(help = "extra argument passed to pytest (repeatable)")]
+    pub pytest_args: Vec<String>,
+}
+
+enum Outcome {
+    Passed,
+    Failed,
+    BuildFailed(String),
+}
+
+/// Build the app for each device and run the Ragger test suites against it.
+/// Every device is tested even if a previous one failed; the result is an
+/// error if any of them did not pass.
+pub fn run_functional_tests(
+    devices: &[Device],
+    args: &RaggerArgs,
+    remaining_args: &[String],
+) -> Result<(), LedgerError> {
+    let mut results: Vec<(Device, Outcome)> = vec![];
+
+    for &device in devices {
+        info!("=== {device} ===");
+        let outcome = match build_app(device, false, remaining_args.to_vec()) {
+            Ok(app) => {
+                place_elf(device, &app.package_path, &app.elf_path)?;
+                let junit_part =
+                    args.junit.as_ref().map(|p| junit_part_path(p, device));
+                if run_pytest(
+                    device,
+                    &app.package_path,
+                    args,
+                    junit_part.as_deref(),
+                )? {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed
+                }
+            }
+            Err(LedgerError::Interrupted) => {
+                return Err(LedgerError::Interrupted);
+            }
+            Err(e) => {
+                eprintln!("Build for {device} failed: {e}");
+                Outcome::BuildFailed(e.to_string())
+            }
+        };
+        results.push((device, outcome));
+    }
+
+    println!("=== Summary ===");
+    for (device, outcome) in &results {
+        let status = match outcome {
+            Outcome::Passed => "passed",
+            Outcome::Failed => "FAILED",
+            Outcome::BuildFailed(_) => "BUILD FAILED",
+        };
+        println!("{device:>10}: {status}");
+    }
+
+    if let Some(junit) = &args.junit {
+        write_junit_report(junit, &results)?;
+        info!("JUnit report written to {junit}");
+    }
+
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|(_, o)| !matches!(o, Outcome::Passed))
+        .map(|(d, _)| d.to_string())
+        .collect();
+    if !failed.is_empty() {
+        return Err(LedgerError::Other(format!(
+            "Functional tests failed on: {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Ragger looks for Rust apps in `<app>/target/<device>/release/`. Copy the
+/// ELF there when cargo wrote it elsewhere (e.g. a workspace target dir).
+fn place_elf(
+    device: Device,
+    package_path: &Utf8Path,
+    elf_path: &Utf8Path,
+) -> Result<(), LedgerError> {
+    let file_name = elf_path
+        .file_name()
+        .ok_or(LedgerError::MissingField("ELF file name"))?;
+    let dir = package_path
+        .join("target")
+        .join(device.as_ref())
+        .join("release");
+    let expected = dir.join(file_name);
+    if expected != elf_path {
+        fs::create_dir_all(&dir)?;
+        fs::copy(elf_path, &expected)?;
+        info!("Copied ELF to {expected}");
+    }
+    Ok(())
+}
+
+fn run_pytest(
+    device: Device,
+    package_path: &Utf8Path,
+    args: &RaggerArgs,
+    junit_part: Option<&Utf8Path>,
+) -> Result<bool, LedgerError> {
+    let mut cmd = Tool::Python.command();
+    cmd.current_dir(package_path)
+        .args(["-m", "pytest"])
+        .arg(&args.tests_dir)
+        .arg("--tb=short")
+        .arg(if verbosity() == Verbosity::Quiet {
+            "-q"
+        } else {
+            "-v"
+        })
+        .arg(if color_stdout() {
+            "--color=yes"
+        } else {
+            "--color=no"
+        })
+        .args(["--device", device.speculos_model()]);
+    if let Some(part) = junit_part {
+        cmd.arg(format!("--junitxml={part}"));
+    }
+    let status = status(cmd.args(&args.pytest_args))?;
+    Ok(status.success())
+}
+
+fn junit_part_path(report: &Utf8Path, device: Device) -> Utf8PathBuf {
+    let stem = report.file_stem().unwrap_or("junit");
+    report.with_file_name(format!("{stem}-{device}.xml"))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Merge the per-device pytest reports into a single `<testsuites>` document,
+/// naming each suite after its device.
+fn write_junit_report(
+    report: &Utf8Path,
+    results: &[(Device, Outcome)],
+) -> Result<(), LedgerError> {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<testsuites>\n",
+    );
+    for (device, outcome) in results {
+        let part = junit_part_path(report, *device);
+        let suites = fs::read_to_string(&part).ok().and_then(|content| {
+            let start = content.find("<testsuite ")?;
+            let end = content.rfind("</testsuites>")?;
+            Some(content[start..end].replacen(
+                "name=\"pytest\"",
+                &format!("name=\"{device}\""),
+                1,
+            ))
+        });
+        let _ = fs::remove_file(&part);
+        match (suites, outcome) {
+            (Some(suites), _) => xml.push_str(&suites),
+            (None, Outcome::BuildFailed(msg)) => {
+                let _ = writeln!(
+                    xml,
+                    "<testsuite name=\"{device}\" tests=\"1\" errors=\"1\" failures=\"0\" skipped=\"0\">\
+                     <testcase classname=\"{device}\" name=\"build\">\
+                     <error message=\"{}\"/></testcase></testsuite>",
+                    xml_escape(msg)
+                );
+            }
+            (None, _) => {
+                let _ = writeln!(
+                    xml,
+                    "<testsuite name=\"{device}\" tests=\"1\" errors=\"1\" failures=\"0\" skipped=\"0\">\
+                     <testcase classname=\"{device}\" name=\"pytest\">\
+                     <error message=\"pytest did not produce a report\"/></testcase></testsuite>"
+                );
+            }
+        }
+    }
+    xml.push_str("</testsuites>\n");
+    if let Some(parent) = report.parent().filter(|p| !p.as_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(report, xml)?;
+    Ok(())
+}
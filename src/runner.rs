@@ -0,0 +1,688 @@
+use cargo_metadata::{Metadata, MetadataCommand};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::Device;
+use crate::error::LedgerError;
+use crate::output::warning;
+use crate::process::{CommandLine, Step, spawn_child, spawn_error};
+
+/// Tool run by the pipeline, whose path can be configured.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tool {
+    Cargo,
+    Objcopy,
+    Size,
+    Python,
+    Speculos,
+}
+
+impl Tool {
+    /// Program run when no path is configured and none is found for
+    /// `device` by the runner.
+    fn fallback_program(self) -> OsString {
+        match self {
+            Tool::Cargo => "cargo".into(),
+            Tool::Objcopy => "arm-none-eabi-objcopy".into(),
+            Tool::Size => "arm-none-eabi-size".into(),
+            Tool::Python => "python3".into(),
+            Tool::Speculos => "speculos".into(),
+        }
+    }
+
+    /// Program run when no path is configured, when building for `device`
+    /// if known. It is looked up once, by the runner.
+    fn default_program(self, device: Option<Device>) -> OsString {
+        static FOUND: Mutex<Vec<(Tool, Option<Device>, OsString)>> =
+            Mutex::new(vec![]);
+        let mut found = FOUND.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((.., program)) =
+            found.iter().find(|(t, d, _)| *t == self && *d == device)
+        {
+            return program.clone();
+        }
+        let program = runner().find_program(self, device);
+        found.push((self, device, program.clone()));
+        program
+    }
+
+    fn configured(self) -> Option<OsString> {
+        TOOLS
+            .get()
+            .and_then(|t| t.iter().rev().find(|t| t.tool == self))
+            .map(|t| t.path.clone().into())
+    }
+
+    /// Program run for this tool, the last path given for it winning.
+    pub fn program(self) -> OsString {
+        self.configured()
+            .unwrap_or_else(|| self.default_program(None))
+    }
+
+    /// Program run for this tool when building for `device`.
+    pub fn program_for(self, device: Device) -> OsString {
+        self.configured()
+            .unwrap_or_else(|| self.default_program(Some(device)))
+    }
+
+    pub fn command(self) -> Command {
+        Command::new(self.program())
+    }
+
+    pub fn command_for(self, device: Device) -> Command {
+        Command::new(self.program_for(device))
+    }
+}
+
+/// Resolve the `name` binutils (objcopy or size) from the
+/// `CARGO_TARGET_<DEVICE>_<NAME>` variable, then the
+/// `CARGO_TARGET_THUMBV6M_NONE_EABI_<NAME>` one historically used for every
+/// device, then the Arm GNU toolchain, then the LLVM tools of the
+/// `llvm-tools` rustup component, or their cargo-binutils wrappers.
+fn binutils_program(name: &str, device: Option<Device>) -> OsString {
+    let var = |target: &str| {
+        format!("CARGO_TARGET_{}_{}", target, name).to_uppercase()
+    };
+    let configured = device
+        .map(|d| var(d.as_ref()))
+        .into_iter()
+        .chain([var("thumbv6m_none_eabi")])
+        .find_map(env::var_os);
+    if let Some(program) = configured {
+        return program;
+    }
+
+    let gnu = format!("arm-none-eabi-{name}");
+    if which(OsStr::new(&gnu)).is_some() {
+        return gnu.into();
+    }
+    let llvm = llvm_tools_dir()
+        .map(|bin| bin.join(format!("llvm-{name}{}", env::consts::EXE_SUFFIX)));
+    if let Some(llvm) = llvm
+        && llvm.is_file()
+    {
+        return llvm.into();
+    }
+    let wrapper = format!("rust-{name}");
+    if which(OsStr::new(&wrapper)).is_some() {
+        return wrapper.into();
+    }
+    // Reported as missing when run
+    gnu.into()
+}
+
+/// Directory of the host tools of the current toolchain, where the
+/// `llvm-tools` component installs them. It is resolved once, with rustc run
+/// outside the runner: only the program found is recorded.
+fn llvm_tools_dir() -> Option<&'static Path> {
+    static DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let rustc = |args: &[&str]| {
+            let out = Command::new("rustc").args(args).output().ok()?;
+            if !out.status.success() {
+                return None;
+            }
+            String::from_utf8(out.stdout).ok()
+        };
+        let sysroot = rustc(&["--print", "sysroot"])?;
+        let version = rustc(&["-vV"])?;
+        let host = version.lines().find_map(|l| l.strip_prefix("host: "))?;
+        Some(
+            Path::new(sysroot.trim())
+                .join("lib")
+                .join("rustlib")
+                .join(host)
+                .join("bin"),
+        )
+    })
+    .as_deref()
+}
+
+/// Resolve `program` the way `Command` does, through `PATH`.
+pub fn which(program: &OsStr) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let name =
+        format!("{}{}", program.to_string_lossy(), env::consts::EXE_SUFFIX);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|p| p.is_file())
+}
+
+/// `<tool>=<path>` given on the command line.
+#[derive(Clone, Debug)]
+pub struct ToolPath {
+    tool: Tool,
+    path: PathBuf,
+}
+
+impl FromStr for ToolPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tool, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <tool>=<path>, got `{s}`"))?;
+        Ok(Self {
+            tool: Tool::from_str(tool.trim(), true)?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// `path` made absolute against the current directory, unless it is a
+/// program name looked up in `PATH`: some commands run in the package
+/// directory, where a relative path would designate another program.
+fn absolute(path: PathBuf) -> Result<PathBuf, LedgerError> {
+    if path.is_relative() && path.components().count() > 1 {
+        Ok(std::path::absolute(path)?)
+    } else {
+        Ok(path)
+    }
+}
+
+static TOOLS: OnceLock<Vec<ToolPath>> = OnceLock::new();
+static RUNNER: OnceLock<Box<dyn Runner>> = OnceLock::new();
+
+/// Arguments passing the configured tool paths on to another cargo-ledger
+/// run.
+pub fn tool_args() -> Vec<String> {
+    TOOLS
+        .get()
+        .into_iter()
+        .flatten()
+        .filter_map(|t| {
+            let tool = t.tool.to_possible_value()?;
+            Some(format!("--tool={}={}", tool.get_name(), t.path.display()))
+        })
+        .collect()
+}
+
+/// Runs the commands of the pipeline, so that they can be recorded or
+/// replayed instead of being run with the real tools.
+pub trait Runner: Send + Sync {
+    /// Program to run for `tool` when no path is configured for it, when
+    /// building for `device` if known.
+    fn find_program(&self, tool: Tool, device: Option<Device>) -> OsString;
+
+    /// Run `cmd` until it exits and return its captured output.
+    fn output(&self, cmd: &mut Command) -> Result<Output, LedgerError>;
+
+    /// Run `cmd` as `step`, passing each line of its stdout to `on_stdout`
+    /// and forwarding its stderr as they come, and return both once it
+    /// exits.
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        step: Step,
+        on_stdout: &mut (dyn FnMut(&[u8]) + Send),
+    ) -> Result<Output, LedgerError>;
+}
+
+/// Apply the tool paths given on the command line, which win over the
+/// interpreter given with `LEDGER_PYTHON`, and select the runner: commands
+/// are recorded into the file given with `CARGO_LEDGER_RECORD`, or replayed
+/// from the one given with `CARGO_LEDGER_REPLAY`.
+pub fn init(tools: Vec<ToolPath>) -> Result<(), LedgerError> {
+    let python = env::var_os("LEDGER_PYTHON").map(|path| ToolPath {
+        tool: Tool::Python,
+        path: path.into(),
+    });
+    let tools = python
+        .into_iter()
+        .chain(tools)
+        .map(|t| {
+            Ok(ToolPath {
+                path: absolute(t.path)?,
+                ..t
+            })
+        })
+        .collect::<Result<_, LedgerError>>()?;
+    let _ = TOOLS.set(tools);
+    let runner: Box<dyn Runner> =
+        if let Some(path) = env::var_os("CARGO_LEDGER_REPLAY") {
+            Box::new(ReplayRunner::load(Path::new(&path))?)
+        } else if let Some(path) = env::var_os("CARGO_LEDGER_RECORD") {
+            Box::new(RecordRunner::create(Path::new(&path))?)
+        } else {
+            Box::new(SystemRunner)
+        };
+    let _ = RUNNER.set(runner);
+    Ok(())
+}
+
+pub fn runner() -> &'static dyn Runner {
+    RUNNER.get_or_init(|| Box::new(SystemRunner)).as_ref()
+}
+
+/// Run `cmd` (e.g. with `--offline`) through the runner with the configured
+/// cargo, and parse its result.
+pub fn metadata(cmd: &mut MetadataCommand) -> Result<Metadata, LedgerError> {
+    let mut command = cmd.cargo_path(Tool::Cargo.program()).cargo_command();
+    let out = crate::process::output(&mut command)?;
+    if !out.status.success() {
+        return Err(LedgerError::command_failure(
+            &command,
+            out.status.code(),
+            &out.stderr,
+        ));
+    }
+    Ok(MetadataCommand::parse(std::str::from_utf8(&out.stdout)?)?)
+}
+
+/// Read `reader` line by line, passing each line to `on_line` as it comes,
+/// and return what was read.
+fn read_lines(reader: impl Read, on_line: &mut dyn FnMut(&[u8])) -> Vec<u8> {
+    let mut read = vec![];
+    let mut reader = BufReader::new(reader);
+    loop {
+        let start = read.len();
+        match reader.read_until(b'\n', &mut read) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => on_line(&read[start..]),
+        }
+    }
+    read
+}
+
+fn forward_stderr(line: &[u8]) {
+    let _ = io::stderr().write_all(line);
+}
+
+/// How long output is still collected once a streamed command exited, from
+/// the processes it started.
+const PIPE_DRAIN: Duration = Duration::from_millis(500);
+
+/// Runs the commands with the real tools.
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn find_program(&self, tool: Tool, device: Option<Device>) -> OsString {
+        match tool {
+            Tool::Objcopy => binutils_program("objcopy", device),
+            Tool::Size => binutils_program("size", device),
+            _ => tool.fallback_program(),
+        }
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output, LedgerError> {
+        cmd.output().map_err(|e| spawn_error(cmd, e))
+    }
+
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        step: Step,
+        on_stdout: &mut (dyn FnMut(&[u8]) + Send),
+    ) -> Result<Output, LedgerError> {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = spawn_child(cmd)?;
+        // Pipes are read on their own threads, so that the time limit and
+        // interruptions are handled while the command runs. They are not
+        // joined, as processes started by the command may keep the pipes
+        // open after it exits: what they write is only collected for
+        // `PIPE_DRAIN` once the command is done.
+        let (tx, rx) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                read_lines(stdout, &mut |l| {
+                    let _ = tx.send((true, l.to_vec()));
+                })
+            });
+        }
+        if let Some(stderr) = child.stderr.take() {
+            std::thread::spawn(move || {
+                read_lines(stderr, &mut |l| {
+                    let _ = tx.send((false, l.to_vec()));
+                })
+            });
+        }
+
+        let exited = &AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let lines = scope.spawn(move || {
+                let (mut stdout, mut stderr) = (vec![], vec![]);
+                let mut drain_deadline = None;
+                loop {
+                    if exited.load(Ordering::SeqCst) {
+                        let deadline = *drain_deadline
+                            .get_or_insert_with(|| Instant::now() + PIPE_DRAIN);
+                        if Instant::now() >= deadline {
+                            break;
+                        }
+                    }
+                    match rx.recv_timeout(Duration::from_millis(50)) {
+                        Ok((true, line)) => {
+                            on_stdout(&line);
+                            stdout.extend(line);
+                        }
+                        Ok((false, line)) => {
+                            forward_stderr(&line);
+                            stderr.extend(line);
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                (stdout, stderr)
+            });
+            let status = child.wait(Some(step));
+            exited.store(true, Ordering::SeqCst);
+            let status = status?;
+            let (stdout, stderr) = lines.join().unwrap_or_default();
+            Ok(Output {
+                status,
+                stdout,
+                stderr,
+            })
+        })
+    }
+}
+
+/// A command run and its result, as recorded.
+#[derive(Serialize, Deserialize, Debug)]
+struct Recorded {
+    program: String,
+    args: Vec<String>,
+    status: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+impl Recorded {
+    /// Whether `cmd` is the recorded command, ignoring the output options
+    /// that depend on whether a terminal is used.
+    fn matches(&self, cmd: &CommandLine) -> bool {
+        self.program == cmd.program
+            && self
+                .args
+                .iter()
+                .map(|a| output_neutral(a))
+                .eq(cmd.args.iter().map(|a| output_neutral(a)))
+    }
+}
+
+/// Program found for a tool with no configured path, as recorded.
+#[derive(Serialize, Deserialize, Debug)]
+struct Found {
+    tool: Tool,
+    device: Option<String>,
+    program: String,
+}
+
+/// Line of a recording.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum Entry {
+    Command(Recorded),
+    Found(Found),
+}
+
+/// `arg` with the colors it selects removed, so that commands run on a
+/// terminal and elsewhere compare equal.
+fn output_neutral(arg: &str) -> &str {
+    if arg.starts_with("--color=") {
+        "--color"
+    } else if arg == "--message-format=json-diagnostic-rendered-ansi" {
+        "--message-format=json"
+    } else {
+        arg
+    }
+}
+
+/// Runs the commands with the real tools, and appends them with their
+/// results to a JSON lines file.
+pub struct RecordRunner {
+    file: Mutex<File>,
+}
+
+impl RecordRunner {
+    fn create(path: &Path) -> Result<Self, LedgerError> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, cmd: &Command, out: &Output) -> Result<(), LedgerError> {
+        let cmd = CommandLine::from(cmd);
+        self.write(&Entry::Command(Recorded {
+            program: cmd.program,
+            args: cmd.args,
+            status: out.status.code(),
+            stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        }))
+    }
+
+    fn write(&self, entry: &Entry) -> Result<(), LedgerError> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+impl Runner for RecordRunner {
+    fn find_program(&self, tool: Tool, device: Option<Device>) -> OsString {
+        let program = SystemRunner.find_program(tool, device);
+        let found = Found {
+            tool,
+            device: device.map(|d| d.as_ref().to_owned()),
+            program: program.to_string_lossy().into_owned(),
+        };
+        if let Err(e) = self.write(&Entry::Found(found)) {
+            warning!("Failed to record {}: {e}", program.to_string_lossy());
+        }
+        program
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output, LedgerError> {
+        let out = SystemRunner.output(cmd)?;
+        self.record(cmd, &out)?;
+        Ok(out)
+    }
+
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        step: Step,
+        on_stdout: &mut (dyn FnMut(&[u8]) + Send),
+    ) -> Result<Output, LedgerError> {
+        let out = SystemRunner.stream(cmd, step, on_stdout)?;
+        self.record(cmd, &out)?;
+        Ok(out)
+    }
+}
+
+/// Replays the commands recorded by `RecordRunner`, in order, without
+/// running anything. Each command must match the recorded one. Programs are
+/// the ones found when recording, so that nothing depends on the tools
+/// installed.
+pub struct ReplayRunner {
+    recorded: Mutex<std::vec::IntoIter<Recorded>>,
+    found: Vec<Found>,
+}
+
+impl ReplayRunner {
+    fn load(path: &Path) -> Result<Self, LedgerError> {
+        let entries = fs::read_to_string(path)?
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Entry>, _>>()?;
+        let (mut recorded, mut found) = (vec![], vec![]);
+        for entry in entries {
+            match entry {
+                Entry::Command(r) => recorded.push(r),
+                Entry::Found(f) => found.push(f),
+            }
+        }
+        Ok(Self {
+            recorded: Mutex::new(recorded.into_iter()),
+            found,
+        })
+    }
+
+    fn next(&self, cmd: &Command) -> Result<Output, LedgerError> {
+        let cmd = CommandLine::from(cmd);
+        let mut recorded =
+            self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(next) = recorded.next() else {
+            return Err(LedgerError::Other(format!(
+                "Replay: no recorded command left for `{cmd}`"
+            )));
+        };
+        if !next.matches(&cmd) {
+            return Err(LedgerError::Other(format!(
+                "Replay: expected `{} {}`, got `{cmd}`",
+                next.program,
+                next.args.join(" ")
+            )));
+        }
+        Ok(Output {
+            status: exit_status(next.status),
+            stdout: next.stdout.into_bytes(),
+            stderr: next.stderr.into_bytes(),
+        })
+    }
+}
+
+impl Runner for ReplayRunner {
+    fn find_program(&self, tool: Tool, device: Option<Device>) -> OsString {
+        let device = device.map(|d| d.as_ref().to_owned());
+        self.found
+            .iter()
+            .find(|f| f.tool == tool && f.device == device)
+            .map(|f| f.program.clone().into())
+            .unwrap_or_else(|| tool.fallback_program())
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output, LedgerError> {
+        self.next(cmd)
+    }
+
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        _step: Step,
+        on_stdout: &mut (dyn FnMut(&[u8]) + Send),
+    ) -> Result<Output, LedgerError> {
+        let out = self.next(cmd)?;
+        read_lines(&out.stdout[..], on_stdout);
+        read_lines(&out.stderr[..], &mut forward_stderr);
+        Ok(out)
+    }
+}
+
+/// Exit status of a recorded command, `None` standing for a signal.
+#[cfg(unix)]
+fn exit_status(code: Option<i32>) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    match code {
+        Some(code) => ExitStatus::from_raw(code << 8),
+        None => ExitStatus::from_raw(libc::SIGKILL),
+    }
+}
+
+#[cfg(windows)]
+fn exit_status(code: Option<i32>) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code.unwrap_or(1) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_path() {
+        let t: ToolPath = "objcopy=/opt/arm/bin/arm-none-eabi-objcopy"
+            .parse()
+            .unwrap();
+        assert_eq!(t.tool, Tool::Objcopy);
+        assert_eq!(t.path, Path::new("/opt/arm/bin/arm-none-eabi-objcopy"));
+
+        let t: ToolPath = "Python=.venv/bin/python".parse().unwrap();
+        assert_eq!(t.tool, Tool::Python);
+        assert_eq!(t.path, Path::new(".venv/bin/python"));
+
+        assert!("objcopy".parse::<ToolPath>().is_err());
+        assert!("gcc=/usr/bin/gcc".parse::<ToolPath>().is_err());
+    }
+
+    #[test]
+    fn absolute_tool_paths() {
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            absolute(".venv/bin/python".into()).unwrap(),
+            cwd.join(".venv/bin/python")
+        );
+        // Looked up in PATH
+        assert_eq!(absolute("python3".into()).unwrap(), Path::new("python3"));
+        assert_eq!(
+            absolute("/usr/bin/python3".into()).unwrap(),
+            Path::new("/usr/bin/python3")
+        );
+    }
+
+    #[test]
+    fn replay_ignores_colors() {
+        let recorded = Recorded {
+            program: "cargo".into(),
+            args: vec![
+                "build".into(),
+                "--message-format=json-diagnostic-rendered-ansi".into(),
+                "--color=always".into(),
+            ],
+            status: Some(0),
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        let mut cmd = Command::new("cargo");
+        cmd.args(["build", "--message-format=json", "--color=never"]);
+        assert!(recorded.matches(&CommandLine::from(&cmd)));
+
+        let mut cmd = Command::new("cargo");
+        cmd.args(["build", "--message-format=short", "--color=never"]);
+        assert!(!recorded.matches(&CommandLine::from(&cmd)));
+    }
+
+    #[test]
+    fn recorded_exit_status() {
+        assert_eq!(exit_status(Some(0)).code(), Some(0));
+        assert_eq!(exit_status(Some(101)).code(), Some(101));
+        assert!(!exit_status(None).success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stream_ignores_leftover_processes() {
+        // The background sleep keeps stdout and stderr open
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo done; sleep 10 &"]);
+        let start = Instant::now();
+        let output = SystemRunner
+            .stream(&mut cmd, Step::Export, &mut |_| {})
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+    }
+}
@@ -1,135 +1,1317 @@
+use crate::Device;
 use crate::error::LedgerError;
-use std::path::Path;
+use crate::manifest::{InstalledFile, SetupManifest, sha256_hex};
+use crate::output::{self, error_label, highlight, info, warning};
+use crate::process::{CommandLine, output};
+use crate::runner::metadata;
+use crate::utils::write_atomic;
+use cargo_metadata::Metadata;
+use cargo_metadata::semver::Version;
+use clap::{Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn install_targets(t: Option<String>) -> Result<(), LedgerError> {
-    println!("[ ] Install custom targets...");
-    // Check if target files are installed
-    let mut args: Vec<String> = vec![];
-
-    args.push(String::from("--print"));
-    args.push(String::from("sysroot"));
-    let sysroot_out = Command::new("rustc").args(&args).output()?;
-    if !sysroot_out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "rustc",
-            status: sysroot_out.status.code(),
-            stderr: String::from_utf8_lossy(&sysroot_out.stderr).into(),
-        });
+/// Crate of the SDK providing the target files and the link script.
+const SYS_CRATE: &str = "ledger_secure_sdk_sys";
+/// Crate of the SDK used by apps, depending on `SYS_CRATE`.
+const DEVICE_SDK_CRATE: &str = "ledger_device_sdk";
+/// Linker wrapper handling relocation, installed next to rust-lld.
+pub const LINK_SCRIPT: &str = "link_wrap.sh";
+/// Directory of the project's target dir holding project-local targets.
+const PROJECT_TARGETS_DIR: &str = "ledger-targets";
+/// Retry policy of the target files downloads.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(2);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// crates.io sparse index entry listing the releases of `SYS_CRATE`, looked
+/// up to suggest an SDK version in hints.
+const SDK_INDEX_URL: &str =
+    "https://index.crates.io/le/dg/ledger_secure_sdk_sys";
+const INDEX_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(clap::Args, Debug)]
+pub struct SetupArgs {
+    #[clap(short, long)]
+    #[clap(help = "git tag or branch to use")]
+    pub tag: Option<String>,
+    #[clap(long, conflicts_with_all = ["tag", "offline"])]
+    #[clap(
+        help = "install from a local SDK checkout or ledger_secure_sdk_sys sources"
+    )]
+    pub from_path: Option<PathBuf>,
+    #[clap(long, conflicts_with = "tag")]
+    #[clap(
+        help = "install from the ledger_secure_sdk_sys sources resolved in Cargo.lock"
+    )]
+    pub offline: bool,
+    #[clap(long, env = "CARGO_LEDGER_SDK_URL")]
+    #[clap(
+        help = "base URL of a ledger_secure_sdk_sys mirror, where {tag} is replaced by the SDK tag (ignored with --from-path and --offline)"
+    )]
+    pub base_url: Option<String>,
+    #[clap(long)]
+    #[clap(help = "only compare the installed files with the SDK ones")]
+    pub check: bool,
+    #[clap(long, conflicts_with = "check")]
+    #[clap(help = "overwrite locally modified files")]
+    pub force: bool,
+    #[clap(long, conflicts_with = "check")]
+    #[clap(
+        help = "print the files that would be downloaded and written, without doing it"
+    )]
+    pub dry_run: bool,
+    #[clap(long = "device", value_enum)]
+    #[clap(
+        help = "device to install the target of (repeatable, default: all)"
+    )]
+    pub devices: Vec<Device>,
+    #[clap(flatten)]
+    pub location: TargetLocation,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SetupCommand {
+    #[clap(about = "remove the files installed by setup")]
+    Uninstall {
+        #[clap(flatten)]
+        location: TargetLocation,
+        #[clap(long)]
+        #[clap(help = "also remove locally modified files")]
+        force: bool,
+    },
+}
+
+/// Where `setup` installs the target files.
+#[derive(clap::Args, Debug)]
+pub struct TargetLocation {
+    #[clap(long, conflicts_with = "all_toolchains")]
+    #[clap(help = "rustup toolchain to use (repeatable)")]
+    pub toolchain: Vec<String>,
+    #[clap(long)]
+    #[clap(help = "use every rustup toolchain")]
+    pub all_toolchains: bool,
+    #[clap(long, conflicts_with_all = ["toolchain", "all_toolchains"])]
+    #[clap(
+        help = "use the project's target/ledger-targets instead of the sysroot"
+    )]
+    pub local: bool,
+}
+
+/// Where the target files and the link script are retrieved from.
+enum TargetSource {
+    /// Base URL of the `ledger_secure_sdk_sys` directory of the SDK
+    /// repository, and the git tag it points to (`None` for master)
+    Remote { url: String, tag: Option<String> },
+    /// `ledger_secure_sdk_sys` sources on disk, and their version if known
+    Local {
+        path: PathBuf,
+        version: Option<String>,
+    },
+}
+
+impl Display for TargetSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetSource::Remote { url, .. } => f.write_str(url),
+            TargetSource::Local { path, .. } => {
+                write!(f, "{}", path.display())
+            }
+        }
     }
-    let sysroot_cmd = std::str::from_utf8(&sysroot_out.stdout)
-        .map_err(|e| LedgerError::Other(format!("utf8 sysroot error: {e}")))?
-        .trim();
+}
 
-    let git_path = format!(
-        "https://raw.githubusercontent.com/LedgerHQ/ledger-device-rust-sdk/{}/ledger_secure_sdk_sys",
-        t.unwrap_or_else(|| "refs/heads/master".to_string())
+impl TargetSource {
+    /// Source selected by `args`. The mirror URL, often exported in CI, is
+    /// ignored when installing from local sources.
+    fn from_args(args: &SetupArgs) -> Result<Self, LedgerError> {
+        if let Some(path) = &args.from_path {
+            return Self::local(path, None);
+        }
+        if args.offline {
+            let options = if args.dry_run { LOCKED } else { OFFLINE };
+            let sys = resolve_sdk_package(&[SYS_CRATE], options)?;
+            let dir = sys
+                .manifest_path
+                .parent()
+                .ok_or(LedgerError::MissingField("package parent path"))?;
+            info!("* Using {SYS_CRATE} {} from {dir}", sys.version);
+            return Self::local(
+                dir.as_std_path(),
+                Some(format!("{SYS_CRATE}@{}", sys.version)),
+            );
+        }
+        let tag = sdk_tag(args.tag.as_deref(), args.dry_run);
+        let url = match &args.base_url {
+            Some(base) => base
+                .trim_end_matches('/')
+                .replace("{tag}", tag.as_deref().unwrap_or("master")),
+            None => format!(
+                "https://raw.githubusercontent.com/LedgerHQ/ledger-device-rust-sdk/{}/{SYS_CRATE}",
+                tag.as_deref().unwrap_or("refs/heads/master")
+            ),
+        };
+        Ok(TargetSource::Remote { url, tag })
+    }
+
+    /// Accept either the `ledger_secure_sdk_sys` sources or a clone of the
+    /// whole SDK repository.
+    fn local(
+        path: &Path,
+        version: Option<String>,
+    ) -> Result<Self, LedgerError> {
+        let nested = path.join(SYS_CRATE);
+        let path = if nested.join("devices").is_dir() {
+            nested
+        } else if path.join("devices").is_dir() {
+            path.to_path_buf()
+        } else {
+            return Err(LedgerError::Other(format!(
+                "No target files found in {} (expected a 'devices' directory)",
+                path.display()
+            )));
+        };
+        Ok(TargetSource::Local { path, version })
+    }
+
+    /// SDK version of the files, when known.
+    fn version(&self) -> Option<&str> {
+        match self {
+            TargetSource::Remote { tag, .. } => tag.as_deref(),
+            TargetSource::Local { version, .. } => version.as_deref(),
+        }
+    }
+
+    /// URL or path `file` is retrieved from.
+    fn origin(&self, file: &str) -> String {
+        match self {
+            TargetSource::Remote { url, .. } => format!("{url}/{file}"),
+            TargetSource::Local { path, .. } => {
+                path.join(file).display().to_string()
+            }
+        }
+    }
+
+    /// Retrieve `file`, given relative to the `ledger_secure_sdk_sys` root.
+    fn fetch(&self, file: &str) -> Result<Vec<u8>, LedgerError> {
+        match self {
+            TargetSource::Remote { url, .. } => {
+                download(&format!("{url}/{file}"))
+            }
+            TargetSource::Local { path, .. } => Ok(fs::read(path.join(file))?),
+        }
+    }
+}
+
+/// Retrieve `url`, retrying transient failures. `file://` URLs are read from
+/// disk.
+fn download(url: &str) -> Result<Vec<u8>, LedgerError> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Ok(fs::read(path)?);
+    }
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(DOWNLOAD_TIMEOUT))
+        .build()
+        .into();
+    let mut attempt = 1;
+    loop {
+        let res = agent
+            .get(url)
+            .call()
+            .and_then(|mut resp| resp.body_mut().read_to_vec());
+        match res {
+            Ok(content) => return Ok(content),
+            // Client errors (e.g. 404) will not go away by retrying
+            Err(ureq::Error::StatusCode(code))
+                if (400..500).contains(&code) =>
+            {
+                return Err(LedgerError::Download {
+                    url: url.into(),
+                    source: ureq::Error::StatusCode(code),
+                });
+            }
+            Err(e) if attempt == DOWNLOAD_ATTEMPTS => {
+                return Err(LedgerError::Download {
+                    url: url.into(),
+                    source: e,
+                });
+            }
+            Err(e) => {
+                info!("* Download of {url} failed ({e}), retrying...");
+                std::thread::sleep(DOWNLOAD_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// First of the `names` packages resolved in the project's Cargo.lock.
+fn resolve_sdk_package(
+    names: &[&str],
+    options: &[&str],
+) -> Result<cargo_metadata::Package, LedgerError> {
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    cmd.other_options(
+        options.iter().map(|o| o.to_string()).collect::<Vec<_>>(),
     );
-    let sys_crate_path = Path::new(&git_path);
+    let res = metadata(&mut cmd)?;
+    names
+        .iter()
+        .find_map(|name| res.packages.iter().find(|p| p.name == *name))
+        .cloned()
+        .ok_or_else(|| {
+            LedgerError::Other(format!(
+                "{} is not a dependency of this project",
+                names.join(" nor ")
+            ))
+        })
+}
 
-    let target_files_url = sys_crate_path.join("devices");
-    let sysroot = Path::new(sysroot_cmd).join("lib").join("rustlib");
+/// `cargo metadata` options resolving the dependencies without network access.
+pub const OFFLINE: &[&str] = &["--offline"];
+/// `cargo metadata` options resolving the dependencies from the existing
+/// `Cargo.lock` only, without network access nor writing it.
+pub const LOCKED: &[&str] = &["--offline", "--locked"];
 
-    // Retrieve each target file independently
-    // TODO: handle target.json modified upstream
-    for target in &["nanox", "nanosplus", "stax", "flex", "apex_p"] {
-        let outfilepath = sysroot.join(target).join("target.json");
-        let targetpath =
-            outfilepath.clone().into_os_string().into_string().map_err(
-                |_| {
-                    LedgerError::Other("Invalid target path (non UTF-8)".into())
-                },
-            )?;
-        println!(
-            "* Adding \x1b[1;32m{target}\x1b[0m in \x1b[1;33m{targetpath}\x1b[0m"
+/// SDK release used by the project, as the `<crate>@<version>` tag the SDK
+/// repository gives to each crate release. Dependencies are resolved by
+/// `cargo metadata` with `options`.
+pub fn project_sdk_version(options: &[&str]) -> Result<String, LedgerError> {
+    let pkg = resolve_sdk_package(&[SYS_CRATE, DEVICE_SDK_CRATE], options)?;
+    Ok(format!("{}@{}", pkg.name, pkg.version))
+}
+
+/// Git tag or branch to retrieve the files from: the one given by the user,
+/// else the tag matching the SDK version used by the project, else `None`
+/// for master. With `dry_run`, the project's `Cargo.lock` is only read.
+fn sdk_tag(tag: Option<&str>, dry_run: bool) -> Option<String> {
+    if let Some(tag) = tag {
+        info!("* Using SDK version {tag} (given with --tag)");
+        return Some(tag.to_string());
+    }
+    match project_sdk_version(if dry_run { LOCKED } else { &[] }) {
+        Ok(tag) => {
+            info!(
+                "* Using SDK version {tag} (resolved in the project's Cargo.lock)"
+            );
+            Some(tag)
+        }
+        Err(e) => {
+            let reason = e.to_string();
+            let reason = reason.lines().next().unwrap_or_default();
+            info!(
+                "* Using SDK master branch (no project SDK version: {reason})"
+            );
+            None
+        }
+    }
+}
+
+/// Run `rustc` of `toolchain` (the current one if `None`) with `args` and
+/// return its trimmed standard output.
+pub fn rustc_output(
+    toolchain: Option<&str>,
+    args: &[&str],
+) -> Result<String, LedgerError> {
+    let mut cmd = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    let out = output(cmd.args(args))?;
+    if !out.status.success() {
+        return Err(LedgerError::command_failure(
+            &cmd,
+            out.status.code(),
+            &out.stderr,
+        ));
+    }
+    Ok(std::str::from_utf8(&out.stdout)
+        .map_err(|e| LedgerError::Other(format!("utf8 rustc error: {e}")))?
+        .trim()
+        .to_string())
+}
+
+/// `lib/rustlib` directory of `toolchain` (the current one if `None`), where
+/// custom targets and the setup manifest are installed.
+pub fn rustlib_dir(toolchain: Option<&str>) -> Result<PathBuf, LedgerError> {
+    let sysroot = rustc_output(toolchain, &["--print", "sysroot"])?;
+    Ok(Path::new(&sysroot).join("lib").join("rustlib"))
+}
+
+/// Directory of the project-local targets installed by `setup --local`.
+pub fn project_targets_dir() -> Result<PathBuf, LedgerError> {
+    let res = metadata(cargo_metadata::MetadataCommand::new().no_deps())?;
+    Ok(local_targets_dir(&res))
+}
+
+fn local_targets_dir(project: &Metadata) -> PathBuf {
+    project
+        .target_directory
+        .join(PROJECT_TARGETS_DIR)
+        .into_std_path_buf()
+}
+
+pub fn sysroot_target_path(rustlib: &Path, device: Device) -> PathBuf {
+    rustlib.join(device.as_ref()).join("target.json")
+}
+
+pub fn project_target_path(dir: &Path, device: Device) -> PathBuf {
+    dir.join(format!("{device}.json"))
+}
+
+/// Custom targets used by the builds of a project: the project-local ones
+/// if installed, else those of the current toolchain. Their directory also
+/// holds the setup manifest.
+pub struct BuildTargets {
+    dir: PathBuf,
+    local: bool,
+}
+
+impl BuildTargets {
+    /// Locate the targets of `project`, given by `cargo metadata`.
+    pub fn locate(project: &Metadata) -> Result<Self, LedgerError> {
+        let dir = local_targets_dir(project);
+        if dir.is_dir() {
+            return Ok(Self { dir, local: true });
+        }
+        Ok(Self {
+            dir: rustlib_dir(None)?,
+            local: false,
+        })
+    }
+
+    fn target_path(&self, device: Device) -> PathBuf {
+        if self.local {
+            project_target_path(&self.dir, device)
+        } else {
+            sysroot_target_path(&self.dir, device)
+        }
+    }
+
+    /// Make `cargo` use project-local targets. Their directory is added to
+    /// `RUST_TARGET_PATH` for rustc to find the target specs, and to `PATH`
+    /// for it to find the link script. rustc finds the sysroot ones itself.
+    pub fn apply(&self, cargo: &mut Command) -> Result<(), LedgerError> {
+        if !self.local {
+            return Ok(());
+        }
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let path = std::env::join_paths(
+            std::iter::once(self.dir.clone())
+                .chain(std::env::split_paths(&path)),
+        )
+        .map_err(|e| LedgerError::Other(format!("Invalid PATH: {e}")))?;
+        cargo.env("RUST_TARGET_PATH", &self.dir).env("PATH", path);
+        Ok(())
+    }
+}
+
+/// Make `cargo` use the project-local targets, if they were installed.
+pub fn use_project_targets(cargo: &mut Command) -> Result<(), LedgerError> {
+    let Ok(dir) = project_targets_dir() else {
+        return Ok(());
+    };
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    BuildTargets { dir, local: true }.apply(cargo)
+}
+
+/// Names of the toolchains installed with rustup.
+fn rustup_toolchains() -> Result<Vec<String>, LedgerError> {
+    let mut cmd = Command::new("rustup");
+    cmd.args(["toolchain", "list"]);
+    let out = output(&mut cmd)?;
+    if !out.status.success() {
+        return Err(LedgerError::command_failure(
+            &cmd,
+            out.status.code(),
+            &out.stderr,
+        ));
+    }
+    // Lines look like `stable-x86_64-unknown-linux-gnu (active, default)`
+    Ok(std::str::from_utf8(&out.stdout)?
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .map(String::from)
+        .collect())
+}
+
+/// Warn when the installed target files come from another SDK version than
+/// the one used by the project, resolved by `cargo metadata` with `options`.
+/// Nothing is reported if either is unknown.
+pub fn check_installed_sdk_version(targets: &BuildTargets, options: &[&str]) {
+    let Some(installed) = SetupManifest::load(&targets.dir)
+        .ok()
+        .flatten()
+        .and_then(|m| m.sdk_version)
+    else {
+        return;
+    };
+    // Only resolved when needed, as it resolves every dependency
+    let Ok(project) = project_sdk_version(options) else {
+        return;
+    };
+    if installed != project {
+        warning!(
+            "custom targets were installed from SDK {installed} but the \
+             project uses {project}, run `cargo ledger setup` to update them"
         );
+    }
+}
+
+/// A file to install and the content retrieved for it.
+struct PlannedFile {
+    path: PathBuf,
+    content: Vec<u8>,
+}
+
+enum FileState {
+    Missing,
+    UpToDate,
+    /// Installed by setup and unchanged since, but differs from the
+    /// retrieved content; comes with a readable diff
+    Outdated(String),
+    /// Differs from the retrieved content, with no hash recorded for it
+    /// (installed by a previous cargo-ledger version, or by hand); comes
+    /// with a readable diff
+    Unrecorded(String),
+    /// Changed since it was installed, and differs from the retrieved
+    /// content; comes with a readable diff
+    Modified(String),
+}
+
+impl PlannedFile {
+    fn state(
+        &self,
+        manifest: Option<&SetupManifest>,
+    ) -> Result<FileState, LedgerError> {
+        let installed = match fs::read(&self.path) {
+            Ok(installed) => installed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(FileState::Missing);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if installed == self.content {
+            return Ok(FileState::UpToDate);
+        }
+        let recorded = manifest.and_then(|m| m.recorded_sha256(&self.path));
+        let untouched = recorded == Some(sha256_hex(&installed).as_str());
+        let installed = String::from_utf8_lossy(&installed);
+        let content = String::from_utf8_lossy(&self.content);
+        let diff = similar::TextDiff::from_lines(&*installed, &*content)
+            .unified_diff()
+            .context_radius(3)
+            .header("installed", "sdk")
+            .to_string();
+        if untouched {
+            Ok(FileState::Outdated(diff))
+        } else if recorded.is_none() {
+            Ok(FileState::Unrecorded(diff))
+        } else {
+            Ok(FileState::Modified(diff))
+        }
+    }
+}
+
+/// Result of loading a target specification with rustc.
+#[derive(Debug, PartialEq)]
+pub enum SpecCheck {
+    Valid,
+    /// Custom targets are only available on nightly toolchains
+    Unsupported,
+    /// rustc rejected the specification, with the reason it gave
+    Invalid(String),
+}
+
+/// Whether rustc of `toolchain` is a nightly (or locally built) one, which
+/// accepts `-Zunstable-options`. `rustc -vV` is only run once per toolchain,
+/// however many targets are checked.
+fn rustc_nightly(toolchain: Option<&str>) -> Result<bool, LedgerError> {
+    static NIGHTLY: Mutex<Vec<(Option<String>, bool)>> = Mutex::new(vec![]);
+    let mut known = NIGHTLY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, nightly)) =
+        known.iter().find(|(t, _)| t.as_deref() == toolchain)
+    {
+        return Ok(*nightly);
+    }
+    let version = rustc_output(toolchain, &["-vV"])?;
+    let nightly = version
+        .lines()
+        .find_map(|l| l.strip_prefix("release: "))
+        .is_some_and(|r| r.contains("nightly") || r.contains("dev"));
+    known.push((toolchain.map(String::from), nightly));
+    Ok(nightly)
+}
+
+/// Have rustc of `toolchain` load the target specification at `path`.
+pub fn check_target_spec(
+    toolchain: Option<&str>,
+    path: &Path,
+) -> Result<SpecCheck, LedgerError> {
+    let nightly = rustc_nightly(toolchain)?;
+    let mut cmd = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    if nightly {
+        cmd.arg("-Zunstable-options");
+    }
+    let out = output(cmd.args(["--print", "cfg", "--target"]).arg(path))?;
+    if out.status.success() {
+        return Ok(SpecCheck::Valid);
+    }
+
+    Ok(rejection(&String::from_utf8_lossy(&out.stderr), nightly))
+}
+
+/// Why rustc rejected a target specification, from its `stderr`.
+fn rejection(stderr: &str, nightly: bool) -> SpecCheck {
+    let reason = stderr
+        .lines()
+        .find_map(|l| l.split_once("error loading target specification: "))
+        .map(|(_, reason)| reason)
+        .or_else(|| {
+            let line = stderr.lines().next()?;
+            Some(line.strip_prefix("error: ").unwrap_or(line))
+        })
+        .unwrap_or_default();
+    if !nightly && reason.contains("-Zunstable-options") {
+        return SpecCheck::Unsupported;
+    }
+    // Reasons look like `<field>: <error>` when a field is incompatible
+    SpecCheck::Invalid(match reason.split_once(": ") {
+        Some((field, error)) if !field.contains(' ') => {
+            format!("field `{field}`: {error}")
+        }
+        _ => reason.to_string(),
+    })
+}
+
+/// How to get targets compatible with the current rustc, given the SDK
+/// version recorded in the manifest of `manifest_dir`.
+pub fn target_fix_hint(manifest_dir: &Path) -> String {
+    let installed = SetupManifest::load(manifest_dir)
+        .ok()
+        .flatten()
+        .and_then(|m| m.sdk_version);
+    match (installed, project_sdk_version(OFFLINE)) {
+        (Some(installed), Ok(project)) if installed != project => format!(
+            "the targets come from SDK {installed} but the project uses \
+             {project}, run `cargo ledger setup` to install its targets"
+        ),
+        (_, Ok(project)) => match latest_sdk_release() {
+            Some(latest) if latest != project => format!(
+                "the targets of SDK {project} do not support this rustc, \
+                 update the SDK to a release supporting it, possibly its \
+                 latest one {latest} (or use the toolchain the SDK was \
+                 released for), then run `cargo ledger setup`"
+            ),
+            _ => format!(
+                "the targets of SDK {project} do not support this rustc, \
+                 update the SDK to a release supporting it (or use the \
+                 toolchain the SDK was released for), then run `cargo ledger \
+                 setup`"
+            ),
+        },
+        (_, Err(_)) => match latest_sdk_release() {
+            Some(latest) => format!(
+                "run `cargo ledger setup --tag <tag>` with an SDK release \
+                 supporting this rustc, possibly the latest one ({latest})"
+            ),
+            None => "run `cargo ledger setup --tag <tag>` with an SDK \
+                     release supporting this rustc"
+                .into(),
+        },
+    }
+}
+
+/// Latest stable version of a sparse registry `index` entry, which has one
+/// JSON line per published version.
+fn latest_release(index: &str) -> Option<Version> {
+    #[derive(Deserialize)]
+    struct Release {
+        vers: Version,
+        yanked: bool,
+    }
 
-        let target_url =
-            target_files_url.join(format!("{target}/{target}.json"));
-        let cmd = Command::new("curl")
-            .arg("--fail")
-            .arg("--retry")
-            .arg("3")
-            .arg("--retry-delay")
-            .arg("2")
-            .arg("--retry-all-errors")
-            .arg(target_url)
-            .arg("-o")
-            .arg(outfilepath)
-            .arg("--create-dirs")
-            .output()?;
-        if !cmd.status.success() {
-            return Err(LedgerError::CommandFailure {
-                cmd: "curl",
-                status: cmd.status.code(),
-                stderr: String::from_utf8_lossy(&cmd.stderr).into(),
-            });
-        }
-        println!("{}", std::str::from_utf8(&cmd.stderr).unwrap());
-    }
-
-    // Install link_wrap.sh script needed for relocation
-    println!("[ ] Install custom link script...");
-
-    /*  Shall be put at the same place as rust-lld */
-    let custom_link_script = "link_wrap.sh";
-
-    let cmd = Command::new("find")
-        .arg(sysroot_cmd)
-        .arg("-name")
-        .arg("rust-lld")
-        .output()?;
-    if !cmd.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "find",
-            status: cmd.status.code(),
-            stderr: String::from_utf8_lossy(&cmd.stderr).into(),
+    index
+        .lines()
+        .filter_map(|l| serde_json::from_str::<Release>(l).ok())
+        .filter(|r| !r.yanked && r.vers.pre.is_empty())
+        .map(|r| r.vers)
+        .max()
+}
+
+/// Set when cargo must not access the network (`--offline`), so that hints
+/// do not either.
+static NETWORK_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Keep hints from looking up the registry, as with `CARGO_NET_OFFLINE`.
+pub fn disable_network() {
+    NETWORK_DISABLED.store(true, Ordering::Relaxed);
+}
+
+fn network_disabled() -> bool {
+    NETWORK_DISABLED.load(Ordering::Relaxed)
+        || std::env::var_os("CARGO_NET_OFFLINE")
+            .is_some_and(|v| !v.is_empty() && v != "false")
+}
+
+/// Latest release of `SYS_CRATE` published on crates.io, as an SDK tag.
+/// `None` offline, or if the registry cannot be reached in time.
+fn latest_sdk_release() -> Option<String> {
+    static LATEST: OnceLock<Option<String>> = OnceLock::new();
+    LATEST
+        .get_or_init(|| {
+            if network_disabled() {
+                return None;
+            }
+            let agent: ureq::Agent = ureq::Agent::config_builder()
+                .timeout_global(Some(INDEX_TIMEOUT))
+                .build()
+                .into();
+            let index = agent
+                .get(SDK_INDEX_URL)
+                .call()
+                .ok()?
+                .body_mut()
+                .read_to_string()
+                .ok()?;
+            latest_release(&index).map(|v| format!("{SYS_CRATE}@{v}"))
+        })
+        .clone()
+}
+
+/// Check that rustc of `toolchain` accepts each installed target.
+fn validate_targets(
+    toolchain: Option<&str>,
+    manifest_dir: &Path,
+    targets: impl Iterator<Item = (Device, PathBuf)>,
+) -> Result<(), LedgerError> {
+    let mut invalid = 0;
+    for (device, path) in targets {
+        match check_target_spec(toolchain, &path)? {
+            SpecCheck::Valid => {}
+            SpecCheck::Unsupported => {
+                info!(
+                    "* Custom targets require a nightly toolchain, {device} \
+                     target not validated"
+                );
+            }
+            SpecCheck::Invalid(reason) => {
+                invalid += 1;
+                warning!("{device} target is rejected by rustc: {reason}");
+            }
+        }
+    }
+    if invalid > 0 {
+        let rustc_version = rustc_output(toolchain, &["--version"])?;
+        return Err(LedgerError::SetupRequired {
+            message: format!(
+                "{invalid} target(s) are not compatible with {rustc_version}"
+            ),
+            hint: target_fix_hint(manifest_dir),
         });
     }
-    let cmd = cmd.stdout;
-
-    let rust_lld_path = std::str::from_utf8(&cmd).map_err(|e| {
-        LedgerError::Other(format!("utf8 rust-lld path error: {e}"))
-    })?;
-    let end = rust_lld_path.rfind('/').ok_or(LedgerError::Other(
-        "Could not determine rust-lld directory".into(),
-    ))?;
-
-    let outfilepath =
-        sysroot.join(&rust_lld_path[..end]).join(custom_link_script);
-
-    /* Retrieve the linker script */
-    let target_url = sys_crate_path.join(custom_link_script);
-    let curl_out = Command::new("curl")
-        .arg("--fail")
-        .arg("--retry")
-        .arg("3")
-        .arg("--retry-delay")
-        .arg("2")
-        .arg("--retry-all-errors")
-        .arg(target_url)
-        .arg("-o")
-        .arg(&outfilepath)
-        .output()?;
-    if !curl_out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "curl",
-            status: curl_out.status.code(),
-            stderr: String::from_utf8_lossy(&curl_out.stderr).into(),
+    Ok(())
+}
+
+/// Check the target of `device` used by builds before invoking cargo, so
+/// that an incompatible target is reported instead of cargo errors.
+pub fn validate_build_target(
+    targets: &BuildTargets,
+    device: Device,
+) -> Result<(), LedgerError> {
+    let path = targets.target_path(device);
+    if !path.is_file() {
+        let local = if targets.local { " --local" } else { "" };
+        return Err(LedgerError::SetupRequired {
+            message: format!(
+                "No {device} target installed ({} not found)",
+                path.display()
+            ),
+            hint: format!("run `cargo ledger setup{local} --device {device}`"),
         });
     }
+    match check_target_spec(None, &path)? {
+        SpecCheck::Valid | SpecCheck::Unsupported => Ok(()),
+        SpecCheck::Invalid(reason) => {
+            let rustc_version = rustc_output(None, &["--version"])?;
+            Err(LedgerError::SetupRequired {
+                message: format!(
+                    "The installed {device} target ({}) is not compatible \
+                     with {rustc_version}: {reason}",
+                    path.display()
+                ),
+                hint: target_fix_hint(&targets.dir),
+            })
+        }
+    }
+}
+
+/// Files retrieved from the source, installed in every selected toolchain.
+struct SdkFiles {
+    targets: Vec<(Device, Vec<u8>)>,
+    link_script: Vec<u8>,
+}
+
+pub fn install_targets(args: &SetupArgs) -> Result<(), LedgerError> {
+    info!("[ ] Install custom targets...");
+    if args.offline {
+        disable_network();
+    }
+    let source = TargetSource::from_args(args)?;
+    info!("* Retrieving target files from {source}");
+
+    let devices = if args.devices.is_empty() {
+        Device::value_variants()
+    } else {
+        &args.devices[..]
+    };
+    if args.dry_run {
+        return plan_install(&source, devices, &args.location);
+    }
+    // Retrieve each target file independently
+    let mut targets = vec![];
+    for &device in devices {
+        targets.push((
+            device,
+            source.fetch(&format!("devices/{device}/{device}.json"))?,
+        ));
+    }
+    let files = SdkFiles {
+        targets,
+        link_script: source.fetch(LINK_SCRIPT)?,
+    };
+
+    if args.location.local {
+        let dir = project_targets_dir()?;
+        return install_in_project(&dir, &source, &files, args);
+    }
+    for_each_toolchain(&args.location, "Setup", |toolchain| {
+        install_in_toolchain(toolchain, &source, &files, args)
+    })
+}
+
+/// Print where the files of `devices` would be retrieved from and installed
+/// to, without retrieving or writing anything.
+fn plan_install(
+    source: &TargetSource,
+    devices: &[Device],
+    location: &TargetLocation,
+) -> Result<(), LedgerError> {
+    let action = match source {
+        TargetSource::Remote { .. } => "download",
+        TargetSource::Local { .. } => "copy",
+    };
+    let plan = |manifest_dir: &Path,
+                target_path: &dyn Fn(Device) -> PathBuf,
+                link_script_paths: &[PathBuf]| {
+        for &device in devices {
+            let file = format!("devices/{device}/{device}.json");
+            output::planned(
+                action,
+                format!(
+                    "{} -> {}",
+                    source.origin(&file),
+                    target_path(device).display()
+                ),
+            );
+        }
+        for path in link_script_paths {
+            output::planned(
+                action,
+                format!("{} -> {}", source.origin(LINK_SCRIPT), path.display()),
+            );
+        }
+        for path in link_script_paths {
+            let mut chmod = Command::new("chmod");
+            chmod.arg("+x").arg(path);
+            output::planned("run", CommandLine::from(&chmod));
+        }
+        output::planned("write", SetupManifest::path(manifest_dir).display());
+    };
+
+    if location.local {
+        let dir = project_targets_dir()?;
+        plan(
+            &dir,
+            &|device| project_target_path(&dir, device),
+            &[dir.join(LINK_SCRIPT)],
+        );
+        return Ok(());
+    }
+    for_each_toolchain(location, "Dry run", |toolchain| {
+        let sysroot = rustlib_dir(toolchain)?;
+        let link_script_paths: Vec<PathBuf> =
+            rust_lld_dirs(toolchain, &sysroot)?
+                .iter()
+                .map(|dir| dir.join(LINK_SCRIPT))
+                .collect();
+        plan(
+            &sysroot,
+            &|device| sysroot_target_path(&sysroot, device),
+            &link_script_paths,
+        );
+        Ok(())
+    })
+}
+
+/// Remove the files recorded in the setup manifest of `location`.
+pub fn uninstall_targets(
+    location: &TargetLocation,
+    force: bool,
+) -> Result<(), LedgerError> {
+    info!("[ ] Uninstall custom targets...");
+    if location.local {
+        let dir = project_targets_dir()?;
+        uninstall_from(&dir, force)?;
+        // Let build use the sysroot targets again
+        if dir.is_dir() && fs::read_dir(&dir)?.next().is_none() {
+            fs::remove_dir(&dir)?;
+        }
+        return Ok(());
+    }
+    for_each_toolchain(location, "Uninstall", |toolchain| {
+        uninstall_from(&rustlib_dir(toolchain)?, force)
+    })
+}
+
+/// Run `f` for each toolchain selected by `location`, `None` standing for
+/// the current one. With several toolchains, all of them are processed and
+/// the result of each is summarized.
+fn for_each_toolchain(
+    location: &TargetLocation,
+    what: &str,
+    mut f: impl FnMut(Option<&str>) -> Result<(), LedgerError>,
+) -> Result<(), LedgerError> {
+    let toolchains = if location.all_toolchains {
+        rustup_toolchains()?
+    } else if !location.toolchain.is_empty() {
+        location.toolchain.clone()
+    } else {
+        return f(None);
+    };
+
+    let mut results = vec![];
+    for toolchain in &toolchains {
+        info!("[ ] Toolchain {toolchain}...");
+        let res = f(Some(toolchain));
+        if let Err(e) = &res {
+            eprintln!("{}: {e}", error_label("Error"));
+        }
+        results.push((toolchain, res));
+    }
+
+    info!("[ ] Summary:");
+    let mut failed = 0;
+    for (toolchain, res) in &results {
+        match res {
+            Ok(()) => info!("* {toolchain}: ok"),
+            Err(e) => {
+                failed += 1;
+                let reason = e.to_string();
+                let reason = reason.lines().next().unwrap_or_default();
+                info!("* {toolchain}: failed ({reason})");
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(LedgerError::Other(format!(
+            "{what} failed for {failed} of {} toolchain(s)",
+            results.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Remove the files recorded in the manifest of `manifest_dir`, refusing to
+/// remove locally modified ones unless `force` is set.
+fn uninstall_from(manifest_dir: &Path, force: bool) -> Result<(), LedgerError> {
+    let Some(manifest) = SetupManifest::load_or_ignore(manifest_dir, force)?
+    else {
+        info!("* No setup manifest in {}", manifest_dir.display());
+        return Ok(());
+    };
+
+    let mut modified = 0;
+    for file in &manifest.files {
+        match fs::read(&file.path) {
+            Ok(content) if sha256_hex(&content) != file.sha256 => {
+                modified += 1;
+                info!("* {}: locally modified", file.path.display());
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if modified > 0 && !force {
+        return Err(LedgerError::Other(format!(
+            "Refusing to remove {modified} locally modified file(s), use --force to remove them"
+        )));
+    }
+
+    for file in &manifest.files {
+        match fs::remove_file(&file.path) {
+            Ok(()) => info!("* Removed {}", file.path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("* {}: already removed", file.path.display())
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // Targets are installed in a directory of their own, drop it once
+        // empty
+        if let Some(parent) = file.path.parent()
+            && parent.parent() == Some(manifest_dir)
+            && fs::read_dir(parent)?.next().is_none()
+        {
+            fs::remove_dir(parent)?;
+        }
+    }
+    fs::remove_file(SetupManifest::path(manifest_dir))?;
+    info!("* Removed {}", SetupManifest::path(manifest_dir).display());
+    Ok(())
+}
 
-    println!("* Custom link script is {}", outfilepath.display());
+/// Install `files` in the sysroot of `toolchain` (the current one if
+/// `None`).
+fn install_in_toolchain(
+    toolchain: Option<&str>,
+    source: &TargetSource,
+    files: &SdkFiles,
+    args: &SetupArgs,
+) -> Result<(), LedgerError> {
+    let sysroot = rustlib_dir(toolchain)?;
 
-    /* Make the linker script executable */
-    let chmod_out =
-        Command::new("chmod").arg("+x").arg(&outfilepath).output()?;
-    if !chmod_out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "chmod",
-            status: chmod_out.status.code(),
-            stderr: String::from_utf8_lossy(&chmod_out.stderr).into(),
+    let mut planned: Vec<PlannedFile> = files
+        .targets
+        .iter()
+        .map(|(target, content)| PlannedFile {
+            path: sysroot_target_path(&sysroot, *target),
+            content: content.clone(),
+        })
+        .collect();
+
+    // Shall be put at the same place as rust-lld, in every directory rustc
+    // may look the linker up from
+    let lld_dirs = rust_lld_dirs(toolchain, &sysroot)?;
+    if let [dir] = lld_dirs.as_slice() {
+        info!("* rust-lld found in {}", dir.display());
+    } else {
+        info!("* LLD found in several directories:");
+        for dir in &lld_dirs {
+            info!("  - {}", dir.display());
+        }
+    }
+    let mut link_script_paths = vec![];
+    for dir in lld_dirs {
+        let path = dir.join(LINK_SCRIPT);
+        planned.push(PlannedFile {
+            path: path.clone(),
+            content: files.link_script.clone(),
         });
+        link_script_paths.push(path);
     }
+
+    let rustc_version = rustc_output(toolchain, &["--version"])?;
+    install_files(
+        &sysroot,
+        &planned,
+        &link_script_paths,
+        rustc_version,
+        source,
+        args,
+    )?;
+    validate_targets(
+        toolchain,
+        &sysroot,
+        files
+            .targets
+            .iter()
+            .map(|(d, _)| (*d, sysroot_target_path(&sysroot, *d))),
+    )
+}
+
+/// Directory of the host tools of `rustlib`, holding rust-lld and the LLVM
+/// tools of the `llvm-tools` component.
+pub fn host_bin_dir(
+    toolchain: Option<&str>,
+    rustlib: &Path,
+) -> Result<PathBuf, LedgerError> {
+    let version = rustc_output(toolchain, &["-vV"])?;
+    let host = version
+        .lines()
+        .find_map(|l| l.strip_prefix("host: "))
+        .ok_or(LedgerError::MissingField("rustc host triple"))?;
+    Ok(rustlib.join(host).join("bin"))
+}
+
+/// Directories of the host tools of `rustlib` containing the LLD linker.
+/// Toolchains ship `rust-lld` in `bin/`, and its `ld.lld` wrapper (or older
+/// `rust-lld` copy) in `bin/gcc-ld/`, which rustc prepends to the linker
+/// `PATH` when using its self-contained linker.
+pub fn rust_lld_dirs(
+    toolchain: Option<&str>,
+    rustlib: &Path,
+) -> Result<Vec<PathBuf>, LedgerError> {
+    let bin = host_bin_dir(toolchain, rustlib)?;
+    let has_lld = |dir: &Path| {
+        ["rust-lld", "ld.lld"].iter().any(|name| {
+            dir.join(format!("{name}{}", std::env::consts::EXE_SUFFIX))
+                .is_file()
+        })
+    };
+    if !has_lld(&bin) {
+        return Err(LedgerError::Other(format!(
+            "rust-lld not found in {}, is the toolchain complete?",
+            bin.display()
+        )));
+    }
+    Ok([bin.clone(), bin.join("gcc-ld")]
+        .into_iter()
+        .filter(|dir| has_lld(dir))
+        .collect())
+}
+
+/// Install `files` in the project-local directory `dir`, where `build` looks
+/// them up through `RUST_TARGET_PATH`.
+fn install_in_project(
+    dir: &Path,
+    source: &TargetSource,
+    files: &SdkFiles,
+    args: &SetupArgs,
+) -> Result<(), LedgerError> {
+    let mut planned: Vec<PlannedFile> = files
+        .targets
+        .iter()
+        .map(|(target, content)| PlannedFile {
+            path: project_target_path(dir, *target),
+            content: content.clone(),
+        })
+        .collect();
+    let link_script_path = dir.join(LINK_SCRIPT);
+    planned.push(PlannedFile {
+        path: link_script_path.clone(),
+        content: files.link_script.clone(),
+    });
+
+    let rustc_version = rustc_output(None, &["--version"])?;
+    install_files(
+        dir,
+        &planned,
+        &[link_script_path],
+        rustc_version,
+        source,
+        args,
+    )?;
+    validate_targets(
+        None,
+        dir,
+        files
+            .targets
+            .iter()
+            .map(|(d, _)| (*d, project_target_path(dir, *d))),
+    )
+}
+
+/// Where a file installed with no recorded hash is saved before being
+/// overwritten: next to it, with a `.orig` extension added.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".orig");
+    backup.into()
+}
+
+/// Write the `planned` files unless they were locally modified, and record
+/// them in the manifest of `manifest_dir`.
+fn install_files(
+    manifest_dir: &Path,
+    planned: &[PlannedFile],
+    link_script_paths: &[PathBuf],
+    rustc_version: String,
+    source: &TargetSource,
+    args: &SetupArgs,
+) -> Result<(), LedgerError> {
+    let manifest = SetupManifest::load_or_ignore(manifest_dir, args.force)?;
+
+    // Compare with what is installed before touching anything, so that
+    // local modifications are never partially overwritten.
+    let mut modified = 0;
+    let mut outdated = 0;
+    let mut missing = 0;
+    // Files with no recorded hash may hold local modifications, they are
+    // kept aside before being overwritten
+    let mut unrecorded = vec![];
+    for file in planned {
+        let path = file.path.display();
+        match file.state(manifest.as_ref())? {
+            FileState::Missing => {
+                missing += 1;
+                info!("* {path}: not installed");
+            }
+            FileState::UpToDate => info!("* {path}: up to date"),
+            FileState::Outdated(diff) => {
+                outdated += 1;
+                info!("* {path}: outdated, changed upstream");
+                info!("{}", diff.trim_end_matches('\n'));
+            }
+            FileState::Unrecorded(diff) => {
+                outdated += 1;
+                info!("* {path}: outdated");
+                info!("{}", diff.trim_end_matches('\n'));
+                let backup = backup_path(&file.path);
+                if !args.check {
+                    warning!(
+                        "{path} was not installed by this version of setup, \
+                         saving it as {} in case it was modified",
+                        backup.display()
+                    );
+                }
+                unrecorded.push((&file.path, backup));
+            }
+            FileState::Modified(diff) => {
+                modified += 1;
+                info!("* {path}: locally modified");
+                info!("{}", diff.trim_end_matches('\n'));
+            }
+        }
+    }
+
+    if args.check {
+        if modified + outdated + missing > 0 {
+            return Err(LedgerError::Other(format!(
+                "Installed files differ from the requested SDK version \
+                 ({outdated} outdated, {modified} locally modified, {missing} missing)"
+            )));
+        }
+        return Ok(());
+    }
+    if modified > 0 && !args.force {
+        return Err(LedgerError::Other(format!(
+            "Refusing to overwrite {modified} locally modified file(s), use --force to overwrite them"
+        )));
+    }
+
+    for file in planned {
+        let targetpath = file
+            .path
+            .clone()
+            .into_os_string()
+            .into_string()
+            .map_err(|_| {
+                LedgerError::Other("Invalid target path (non UTF-8)".into())
+            })?;
+        if let Some((_, backup)) =
+            unrecorded.iter().find(|(path, _)| **path == file.path)
+        {
+            fs::copy(&file.path, backup)?;
+        }
+        info!("* Writing {}", highlight(targetpath));
+        if let Some(parent) = file.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&file.path, &file.content)?;
+    }
+
+    for link_script_path in link_script_paths {
+        info!("* Custom link script is {}", link_script_path.display());
+
+        /* Make the linker script executable */
+        let mut chmod = Command::new("chmod");
+        chmod.arg("+x").arg(link_script_path);
+        let chmod_out = output(&mut chmod)?;
+        if !chmod_out.status.success() {
+            return Err(LedgerError::command_failure(
+                &chmod,
+                chmod_out.status.code(),
+                &chmod_out.stderr,
+            ));
+        }
+    }
+
+    // Record what was installed, to detect local modifications and SDK
+    // version mismatches later on. Files installed previously for other
+    // devices are kept.
+    let mut installed: Vec<InstalledFile> = manifest
+        .map(|m| m.files)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|f| planned.iter().all(|p| p.path != f.path))
+        .collect();
+    installed.extend(planned.iter().map(|f| InstalledFile {
+        path: f.path.clone(),
+        sha256: sha256_hex(&f.content),
+    }));
+    let manifest = SetupManifest {
+        source: source.to_string(),
+        sdk_version: source.version().map(String::from),
+        rustc_version,
+        installed_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        files: installed,
+    };
+    manifest.save(manifest_dir)?;
+    info!(
+        "* Setup manifest is {}",
+        SetupManifest::path(manifest_dir).display()
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_spec_rejection() {
+        let stderr = "error: error loading target specification: custom \
+                      targets are unstable and require `-Zunstable-options`\n  \
+                      |\n  = help: run `rustc --print target-list` for a list \
+                      of built-in targets\n";
+        assert_eq!(rejection(stderr, false), SpecCheck::Unsupported);
+
+        let stderr = "error: error loading target specification: \
+                      target-pointer-width: invalid type: string \"32\", \
+                      expected u16 at line 17 column 30\n";
+        assert_eq!(
+            rejection(stderr, true),
+            SpecCheck::Invalid(
+                "field `target-pointer-width`: invalid type: string \"32\", \
+                 expected u16 at line 17 column 30"
+                    .into()
+            )
+        );
+
+        let stderr = "error: target file \"/x.json\" does not exist\n";
+        assert_eq!(
+            rejection(stderr, true),
+            SpecCheck::Invalid("target file \"/x.json\" does not exist".into())
+        );
+    }
+
+    #[test]
+    fn latest_stable_release() {
+        let index = concat!(
+            r#"{"name":"ledger_secure_sdk_sys","vers":"1.9.0","yanked":false}"#,
+            "\n",
+            r#"{"name":"ledger_secure_sdk_sys","vers":"1.10.1","yanked":false}"#,
+            "\n",
+            r#"{"name":"ledger_secure_sdk_sys","vers":"1.10.2","yanked":true}"#,
+            "\n",
+            r#"{"name":"ledger_secure_sdk_sys","vers":"1.11.0-rc1","yanked":false}"#,
+            "\n",
+        );
+        assert_eq!(latest_release(index), Some(Version::new(1, 10, 1)));
+        assert_eq!(latest_release(""), None);
+    }
+}
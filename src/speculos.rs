@@ -0,0 +1,178 @@
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use std::process::Command;
+
+use crate::Device;
+use crate::error::LedgerError;
+use crate::output::{self, info};
+use crate::process::{check_interrupted, interrupted, spawn, timeout_args};
+use crate::runner::{Tool, tool_args};
+use crate::setup::use_project_targets;
+use crate::utils::retrieve_infos;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SpeculosArgs {
+    #[clap(long)]
+    #[clap(help = "BIP39 seed used by Speculos")]
+    pub seed: Option<String>,
+    #[clap(long, default_value_t = 5000)]
+    #[clap(help = "port of the Speculos REST API")]
+    pub api_port: u16,
+    #[clap(long, default_value_t = 9999)]
+    #[clap(help = "port of the Speculos APDU server")]
+    pub apdu_port: u16,
+    #[clap(long)]
+    #[clap(help = "Speculos display (headless, qt or text)")]
+    pub display: Option<String>,
+}
+
+/// Address at which Speculos maps the `.text` section of the app.
+pub const APP_LOAD_ADDRESS: u64 = 0x4000_0000;
+
+fn speculos_command(
+    device: Device,
+    elf_path: &Utf8Path,
+    api_level: &str,
+    args: &SpeculosArgs,
+    debug: bool,
+) -> Command {
+    let mut cmd = Tool::Speculos.command();
+    cmd.args(["--model", device.speculos_model()]);
+    if !api_level.is_empty() {
+        cmd.args(["--apiLevel", api_level]);
+    }
+    if let Some(seed) = &args.seed {
+        cmd.args(["--seed", seed]);
+    }
+    if let Some(display) = &args.display {
+        cmd.args(["--display", display]);
+    }
+    cmd.args(["--api-port", &args.api_port.to_string()]);
+    cmd.args(["--apdu-port", &args.apdu_port.to_string()]);
+    if debug {
+        cmd.arg("--debug");
+    }
+    cmd.arg(elf_path);
+    cmd
+}
+
+/// Run the app in Speculos until it exits or is interrupted. With `debug`,
+/// Speculos waits for a GDB connection before starting the app.
+pub fn run_speculos(
+    device: Device,
+    elf_path: &Utf8Path,
+    api_level: &str,
+    args: &SpeculosArgs,
+    debug: bool,
+) -> Result<(), LedgerError> {
+    info!(
+        "Starting Speculos ({}, API level {}) on {}",
+        device.speculos_model(),
+        if api_level.is_empty() {
+            "default"
+        } else {
+            api_level
+        },
+        elf_path
+    );
+    let mut cmd = speculos_command(device, elf_path, api_level, args, debug);
+    let mut child = spawn(&mut cmd)?;
+    let status = child.wait(None)?;
+
+    // An interrupted Speculos never exits successfully, this is the expected
+    // way of stopping it.
+    if interrupted() {
+        info!("Speculos stopped");
+        return Ok(());
+    }
+    if !status.success() {
+        return Err(LedgerError::command_failure(&cmd, status.code(), &[]));
+    }
+    Ok(())
+}
+
+impl SpeculosArgs {
+    /// Command line arguments reproducing these options.
+    fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(seed) = &self.seed {
+            args.push(format!("--seed={seed}"));
+        }
+        if let Some(display) = &self.display {
+            args.push(format!("--display={display}"));
+        }
+        args.push(format!("--api-port={}", self.api_port));
+        args.push(format!("--apdu-port={}", self.apdu_port));
+        args
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Run `cargo test` for `device`, with cargo-ledger registered as the target
+/// runner so that each test binary is executed in Speculos.
+pub fn run_unit_tests(
+    device: Device,
+    args: &SpeculosArgs,
+    remaining_args: &[String],
+) -> Result<(), LedgerError> {
+    let exe = std::env::current_exe()?;
+    let exe = exe.to_str().ok_or_else(|| {
+        LedgerError::Other("Invalid cargo-ledger path (non UTF-8)".into())
+    })?;
+    // The runner is given as a TOML array through `--config` rather than
+    // through `CARGO_TARGET_<triple>_RUNNER`, which cargo splits on
+    // whitespace.
+    let mut runner = vec![exe.to_string(), "ledger".into()];
+    // The runner is run by cargo, with the global options of this run
+    runner.extend(output::cargo_args());
+    runner.extend(timeout_args());
+    runner.extend(tool_args());
+    runner.extend(["runner".into(), device.to_string()]);
+    runner.extend(args.to_args());
+    let runner: Vec<String> = runner.iter().map(|a| toml_string(a)).collect();
+
+    let mut cargo = Tool::Cargo.command();
+    use_project_targets(&mut cargo)?;
+    cargo
+        .arg("--config")
+        .arg(format!("target.{device}.runner=[{}]", runner.join(", ")))
+        .arg("test")
+        .arg(format!("--target={device}"))
+        .args(output::cargo_args())
+        .args(remaining_args);
+    let mut child = spawn(&mut cargo)?;
+    let status = child.wait(None)?;
+    check_interrupted()?;
+    if !status.success() {
+        return Err(LedgerError::command_failure(&cargo, status.code(), &[]));
+    }
+    Ok(())
+}
+
+/// Cargo runner entry point: execute one test binary in a headless Speculos
+/// and return its exit code, which Speculos takes from the semihosting exit
+/// call of the test harness. Arguments given by cargo to the test binary
+/// cannot be forwarded to the emulated app and are ignored.
+pub fn run_test_binary(
+    device: Device,
+    binary: &Utf8PathBuf,
+    args: &SpeculosArgs,
+) -> Result<i32, LedgerError> {
+    // Test binaries carry the same `ledger.*` sections as applications; this
+    // also rejects anything that is not a valid ELF before starting Speculos.
+    let infos = retrieve_infos(binary)?;
+    let mut args = args.clone();
+    args.display.get_or_insert_with(|| "headless".into());
+
+    let mut child = spawn(&mut speculos_command(
+        device,
+        binary,
+        &infos.api_level,
+        &args,
+        false,
+    ))?;
+    let status = child.wait(None)?;
+    Ok(status.code().unwrap_or(1))
+}
@@ -0,0 +1,278 @@
+use cargo_metadata::camino::Utf8Path;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+
+use crate::error::LedgerError;
+
+type Reader<'a> = gimli::EndianSlice<'a, gimli::RunTimeEndian>;
+
+/// Parse an address given in hexadecimal (`0x` prefix) or in decimal.
+pub fn parse_address(s: &str) -> Result<u64, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|e| format!("invalid address '{s}': {e}"))
+}
+
+struct Frame {
+    function: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+}
+
+impl Frame {
+    fn describe(&self) -> String {
+        let mut s = self.function.clone().unwrap_or_else(|| "??".into());
+        if let Some(file) = &self.file {
+            s.push_str(&format!(" at {file}"));
+            if let Some(line) = self.line {
+                s.push_str(&format!(":{line}"));
+                if let Some(column) = self.column {
+                    s.push_str(&format!(":{column}"));
+                }
+            }
+        }
+        s
+    }
+}
+
+fn section<'a>(
+    elf: &goblin::elf::Elf,
+    buffer: &'a [u8],
+    name: &str,
+) -> Option<&'a [u8]> {
+    let sh = elf
+        .section_headers
+        .iter()
+        .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(name))?;
+    if sh.sh_type == goblin::elf::section_header::SHT_NOBITS {
+        return None;
+    }
+    buffer.get(sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize)
+}
+
+struct Symbolizer<'a> {
+    elf: goblin::elf::Elf<'a>,
+    ctx: addr2line::Context<Reader<'a>>,
+    text_addr: u64,
+    load_base: Option<u64>,
+}
+
+impl<'a> Symbolizer<'a> {
+    fn new(
+        buffer: &'a [u8],
+        load_base: Option<u64>,
+    ) -> Result<Self, LedgerError> {
+        let elf = goblin::elf::Elf::parse(buffer)?;
+        let endian = if elf.little_endian {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let data = section(&elf, buffer, id.name()).unwrap_or(&[]);
+            Ok(gimli::EndianSlice::new(data, endian))
+        })?;
+        let ctx = addr2line::Context::from_dwarf(dwarf)?;
+        let text_addr = elf
+            .section_headers
+            .iter()
+            .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(".text"))
+            .map(|s| s.sh_addr)
+            .ok_or(LedgerError::MissingField(".text section"))?;
+        Ok(Self {
+            elf,
+            ctx,
+            text_addr,
+            load_base,
+        })
+    }
+
+    /// Translate a runtime address to its link address. Addresses below the
+    /// load base (RAM, OS) are not relocated. The Thumb bit is cleared.
+    fn link_address(&self, addr: u64) -> u64 {
+        let addr = match self.load_base {
+            Some(base) if addr >= base => addr - base + self.text_addr,
+            _ => addr,
+        };
+        addr & !1
+    }
+
+    /// Name of the function symbol containing `addr`, used when the ELF has
+    /// no debug information for it.
+    fn symbol_name(&self, addr: u64) -> Option<String> {
+        self.elf.syms.iter().find_map(|sym| {
+            let start = sym.st_value & !1;
+            if !sym.is_function()
+                || addr < start
+                || addr >= start + sym.st_size.max(1)
+            {
+                return None;
+            }
+            let name = self.elf.strtab.get_at(sym.st_name)?;
+            Some(format!("{:#}", rustc_demangle::demangle(name)))
+        })
+    }
+
+    /// Whether `addr`, a link address, is in an executable section. DWARF
+    /// keeps entries at address 0 for the functions removed by the linker,
+    /// which must not be matched by register values.
+    fn is_code(&self, addr: u64) -> bool {
+        use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR};
+        self.elf.section_headers.iter().any(|s| {
+            let flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+            s.sh_flags & flags == flags
+                && addr >= s.sh_addr
+                && addr < s.sh_addr + s.sh_size
+        })
+    }
+
+    /// Frames for `addr`, innermost inlined function first. Addresses out of
+    /// the code have none.
+    fn lookup(&self, addr: u64) -> Result<Vec<Frame>, LedgerError> {
+        let probe = self.link_address(addr);
+        let mut frames = vec![];
+        if !self.is_code(probe) {
+            return Ok(frames);
+        }
+        let mut iter = self.ctx.find_frames(probe).skip_all_loads()?;
+        while let Some(frame) = iter.next()? {
+            let function = match &frame.function {
+                Some(f) => Some(f.demangle()?.into_owned()),
+                None => None,
+            };
+            let location = frame.location.as_ref();
+            frames.push(Frame {
+                function,
+                file: location.and_then(|l| l.file).map(String::from),
+                line: location.and_then(|l| l.line),
+                // Column 0 means that the column is unknown
+                column: location.and_then(|l| l.column).filter(|&c| c != 0),
+            });
+        }
+        match frames.first_mut() {
+            Some(frame) if frame.function.is_none() => {
+                frame.function = self.symbol_name(probe);
+            }
+            None => {
+                if let Some(function) = self.symbol_name(probe) {
+                    frames.push(Frame {
+                        function: Some(function),
+                        file: None,
+                        line: None,
+                        column: None,
+                    });
+                }
+            }
+            _ => {}
+        }
+        Ok(frames)
+    }
+}
+
+/// Addresses written as `0x...` in a line of text.
+fn find_addresses(line: &str) -> Vec<u64> {
+    let bytes = line.as_bytes();
+    let mut addrs = vec![];
+    let mut i = 0;
+    while i + 2 < bytes.len() {
+        let is_prefix = bytes[i] == b'0'
+            && bytes[i + 1].eq_ignore_ascii_case(&b'x')
+            && (i == 0 || !bytes[i - 1].is_ascii_alphanumeric());
+        if !is_prefix {
+            i += 1;
+            continue;
+        }
+        let start = i + 2;
+        let mut end = start;
+        while end < bytes.len() && bytes[end].is_ascii_hexdigit() {
+            end += 1;
+        }
+        if end > start
+            && end - start <= 16
+            && let Ok(addr) = u64::from_str_radix(&line[start..end], 16)
+        {
+            addrs.push(addr);
+        }
+        i = end;
+    }
+    addrs
+}
+
+/// Print the function, file and line of each address. With `log`, every
+/// line of the log (`-` for stdin) is printed back, followed by the location
+/// of each address it contains that belongs to a known function.
+pub fn addr2line(
+    elf_path: &Utf8Path,
+    addrs: &[u64],
+    load_base: Option<u64>,
+    log: Option<&Utf8Path>,
+) -> Result<(), LedgerError> {
+    let buffer = fs::read(elf_path)?;
+    let symbolizer = Symbolizer::new(&buffer, load_base)?;
+
+    for &addr in addrs {
+        let link_addr = symbolizer.link_address(addr);
+        if link_addr == addr & !1 {
+            println!("{addr:#010x}");
+        } else {
+            println!("{addr:#010x} ({link_addr:#010x})");
+        }
+        let frames = symbolizer.lookup(addr)?;
+        if frames.is_empty() {
+            println!("    ??");
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            let prefix = if i == 0 { "" } else { "(inlined by) " };
+            println!("    {prefix}{}", frame.describe());
+        }
+    }
+
+    if let Some(log) = log {
+        let reader: Box<dyn BufRead> = if log == "-" {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            Box::new(BufReader::new(fs::File::open(log)?))
+        };
+        for line in reader.lines() {
+            let line = line?;
+            println!("{line}");
+            for addr in find_addresses(&line) {
+                let frames = symbolizer.lookup(addr)?;
+                if let Some(frame) =
+                    frames.first().filter(|f| f.function.is_some())
+                {
+                    println!("    -> {addr:#010x}: {}", frame.describe());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("0x40001234"), Ok(0x40001234));
+        assert_eq!(parse_address("0XfF"), Ok(0xff));
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert!(parse_address("0x").is_err());
+        assert!(parse_address("0x4000zz").is_err());
+    }
+
+    #[test]
+    fn addresses_in_log() {
+        assert_eq!(
+            find_addresses("pc=0x40001234 lr:0X400056A9, sp 0x20001ff8"),
+            [0x40001234, 0x400056a9, 0x20001ff8]
+        );
+        // Not standalone, or too long to be an address
+        assert!(find_addresses("ab0x1234 0x 0x11112222333344445").is_empty());
+        assert_eq!(find_addresses("(0x1)"), [1]);
+    }
+}
@@ -1,12 +1,16 @@
 use cargo_metadata::camino::Utf8Path;
 use cargo_metadata::camino::Utf8PathBuf;
 use std::collections::HashMap;
-use std::env;
 use std::fs;
-use std::io::{self, Write};
 use std::process::Command;
 
+use crate::Device;
 use crate::error::LedgerError;
+use crate::output::warning;
+use cargo_metadata::semver::Version;
+
+use crate::process::{Step, output, output_streamed};
+use crate::runner::Tool;
 
 #[derive(Default, Debug)]
 pub struct LedgerAppInfos {
@@ -19,6 +23,29 @@ pub struct LedgerAppInfos {
     pub install_params_size: u64,
 }
 
+/// Write `content` to a temporary file next to `path`, then rename it over
+/// `path`, so that readers never see a partially written file.
+pub fn write_atomic(
+    path: &std::path::Path,
+    content: &[u8],
+) -> Result<(), LedgerError> {
+    let name = path
+        .file_name()
+        .ok_or(LedgerError::MissingField("file name"))?;
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    if let Err(e) =
+        fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, path))
+    {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
 fn get_string_from_offset(
     vector: &[u8],
     offset: &usize,
@@ -36,7 +63,6 @@ fn get_string_from_offset(
 pub fn retrieve_infos(
     file: &Utf8PathBuf,
 ) -> Result<LedgerAppInfos, LedgerError> {
-    println!("Retrieving Ledger app infos from ELF: {}", file);
     let buffer = fs::read(file)?;
     let elf = goblin::elf::Elf::parse(&buffer)?;
 
@@ -100,55 +126,151 @@ pub fn retrieve_infos(
             _ => (),
         }
     }
-    infos.data_size = envram_data - nvram_data;
-    infos.install_params_size =
-        einstall_parameters_data - install_parameters_data;
+    infos.data_size = envram_data.checked_sub(nvram_data).ok_or_else(|| {
+        LedgerError::InvalidElf("_envram_data is before _nvram_data".into())
+    })?;
+    infos.install_params_size = einstall_parameters_data
+        .checked_sub(install_parameters_data)
+        .ok_or_else(|| {
+            LedgerError::InvalidElf(
+                "_einstall_parameters is before _install_parameters".into(),
+            )
+        })?;
     Ok(infos)
 }
 
+/// objcopy command converting the app ELF to Intel HEX.
+pub fn objcopy_command(
+    device: Device,
+    elf_path: &Utf8Path,
+    dest_bin: &Utf8Path,
+) -> Command {
+    let mut objcopy = Tool::Objcopy.command_for(device);
+    objcopy.arg(elf_path).arg(dest_bin).args(["-O", "ihex"]);
+    objcopy
+}
+
+/// size command printing the app section sizes.
+pub fn size_command(device: Device, elf_path: &Utf8Path) -> Command {
+    let mut size = Tool::Size.command_for(device);
+    size.arg(elf_path);
+    size
+}
+
 pub fn export_binary(
+    device: Device,
     elf_path: &Utf8PathBuf,
     dest_bin: &Utf8PathBuf,
 ) -> Result<(), LedgerError> {
-    let objcopy = env::var_os("CARGO_TARGET_THUMBV6M_NONE_EABI_OBJCOPY")
-        .unwrap_or_else(|| "arm-none-eabi-objcopy".into());
-    let copy_out = Command::new(&objcopy)
-        .arg(elf_path)
-        .arg(dest_bin)
-        .args(["-O", "ihex"])
-        .output()?;
+    let mut objcopy = objcopy_command(device, elf_path, dest_bin);
+    let copy_out = output_streamed(&mut objcopy, Step::Export)?;
     if !copy_out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "objcopy",
-            status: copy_out.status.code(),
-            stderr: String::from_utf8_lossy(&copy_out.stderr).into(),
-        });
+        return Err(LedgerError::command_failure(
+            &objcopy,
+            copy_out.status.code(),
+            &copy_out.stderr,
+        ));
     }
 
-    let size = env::var_os("CARGO_TARGET_THUMBV6M_NONE_EABI_SIZE")
-        .unwrap_or_else(|| "arm-none-eabi-size".into());
-
     // print some size info while we're here
-    let out = Command::new(&size).arg(elf_path).output()?;
+    let mut size = size_command(device, elf_path);
+    let out = output_streamed(&mut size, Step::Export)?;
     if !out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "size",
-            status: out.status.code(),
-            stderr: String::from_utf8_lossy(&out.stderr).into(),
-        });
+        return Err(LedgerError::command_failure(
+            &size,
+            out.status.code(),
+            &out.stderr,
+        ));
     }
 
-    io::stdout().write_all(&out.stdout)?;
-    io::stderr().write_all(&out.stderr)?;
     Ok(())
 }
 
-pub fn dump_with_ledgerblue(
+/// Oldest ledgerblue supporting the `--tlv` and `--installparamsSize`
+/// arguments of `loadApp` as used to package apps.
+pub const MIN_LEDGERBLUE_VERSION: Version = Version::new(0, 1, 48);
+
+/// Parse a Python package version such as `0.1.54` or `0.1.54.post1`,
+/// keeping its release numbers only.
+fn parse_python_version(version: &str) -> Option<Version> {
+    let mut numbers = version.trim().split('.').map(|n| {
+        let digits = n.find(|c: char| !c.is_ascii_digit()).unwrap_or(n.len());
+        n[..digits].parse::<u64>().ok()
+    });
+    let major = numbers.next()??;
+    let minor = numbers.next().flatten().unwrap_or(0);
+    let patch = numbers.next().flatten().unwrap_or(0);
+    Some(Version::new(major, minor, patch))
+}
+
+/// Version of ledgerblue installed for the Python interpreter, `None` if it
+/// is not installed.
+pub fn ledgerblue_version() -> Result<Option<String>, LedgerError> {
+    let mut cmd = Tool::Python.command();
+    cmd.args([
+        "-c",
+        "import importlib.metadata as m; print(m.version('ledgerblue'))",
+    ]);
+    let out = output(&mut cmd)?;
+    if !out.status.success() {
+        if String::from_utf8_lossy(&out.stderr).contains("PackageNotFoundError")
+        {
+            return Ok(None);
+        }
+        return Err(LedgerError::command_failure(
+            &cmd,
+            out.status.code(),
+            &out.stderr,
+        ));
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&out.stdout).trim().to_string(),
+    ))
+}
+
+/// Make sure the installed ledgerblue packages apps the way the SDK expects.
+pub fn check_ledgerblue() -> Result<(), LedgerError> {
+    check_ledgerblue_version(ledgerblue_version()?.as_deref())
+}
+
+/// Make sure the ledgerblue `version` found, if any, is recent enough.
+pub fn check_ledgerblue_version(
+    version: Option<&str>,
+) -> Result<(), LedgerError> {
+    let python = Tool::Python.program().to_string_lossy().into_owned();
+    let Some(version) = version else {
+        return Err(LedgerError::UnsupportedTool {
+            message: format!("ledgerblue is not installed for `{python}`"),
+            hint: format!("{python} -m pip install ledgerblue"),
+        });
+    };
+    match parse_python_version(version) {
+        Some(v) if v < MIN_LEDGERBLUE_VERSION => {
+            Err(LedgerError::UnsupportedTool {
+                message: format!(
+                    "ledgerblue {version} is too old, {MIN_LEDGERBLUE_VERSION} \
+                     or later is required for `--tlv` and `--installparamsSize`"
+                ),
+                hint: format!("{python} -m pip install --upgrade ledgerblue"),
+            })
+        }
+        Some(_) => Ok(()),
+        None => {
+            warning!("could not parse the ledgerblue version `{version}`");
+            Ok(())
+        }
+    }
+}
+
+/// ledgerblue command generating the APDU file `out_file_name`.
+pub fn ledgerblue_dump_command(
     dir: &Utf8Path,
     params: &HashMap<String, String>,
-    out_file_name: &Utf8PathBuf,
-) -> Result<(), LedgerError> {
-    let out = Command::new("python3")
+    out_file_name: &Utf8Path,
+) -> Command {
+    let mut cmd = Tool::Python.command();
+    // Let Python flush each line as it is printed
+    cmd.env("PYTHONUNBUFFERED", "1")
         .current_dir(dir)
         .args(["-m", "ledgerblue.loadApp"])
         .args(["--targetId", params["targetId"].as_str()])
@@ -161,17 +283,41 @@ pub fn dump_with_ledgerblue(
         .arg("--tlv")
         .args(["--dataSize", params["dataSize"].as_str()])
         .args(["--installparamsSize", params["installParamsSize"].as_str()])
-        .args(["--offline", out_file_name.as_str()])
-        .output()?;
+        .args(["--offline", out_file_name.as_str()]);
+    cmd
+}
+
+/// ledgerblue command loading the APDU file `out_file_name` on a device.
+pub fn ledgerblue_install_command(
+    dir: &Utf8Path,
+    params: &HashMap<String, String>,
+    out_file_name: &Utf8Path,
+) -> Command {
+    let mut cmd = Tool::Python.command();
+    // Let Python flush each line as it is printed
+    cmd.env("PYTHONUNBUFFERED", "1")
+        .current_dir(dir)
+        .args(["-m", "ledgerblue.runScript"])
+        .args(["--targetId", params["targetId"].as_str()])
+        .args(["--fileName", out_file_name.as_str()])
+        .args(["--apdu", "--scp"]);
+    cmd
+}
+
+pub fn dump_with_ledgerblue(
+    dir: &Utf8Path,
+    params: &HashMap<String, String>,
+    out_file_name: &Utf8PathBuf,
+) -> Result<(), LedgerError> {
+    let mut cmd = ledgerblue_dump_command(dir, params, out_file_name);
+    let out = output_streamed(&mut cmd, Step::Package)?;
     if !out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "python3 -m ledgerblue.loadApp",
-            status: out.status.code(),
-            stderr: String::from_utf8_lossy(&out.stderr).into(),
-        });
+        return Err(LedgerError::command_failure(
+            &cmd,
+            out.status.code(),
+            &out.stderr,
+        ));
     }
-    io::stdout().write_all(&out.stdout)?;
-    io::stderr().write_all(&out.stderr)?;
 
     // Extract the application hash printed by ledgerblue on stdout and store it
     // in a .sha256 file next to the .apdu file.
@@ -183,8 +329,8 @@ pub fn dump_with_ledgerblue(
         let sha256_path = out_file_name.with_extension("sha256");
         fs::write(&sha256_path, format!("{hash}\n"))?;
     } else {
-        eprintln!(
-            "Warning: could not find 'Application full hash' in ledgerblue output, \
+        warning!(
+            "could not find 'Application full hash' in ledgerblue output, \
              skipping .sha256 file generation"
         );
     }
@@ -197,21 +343,51 @@ pub fn install_with_ledgerblue(
     params: &HashMap<String, String>,
     out_file_name: &Utf8PathBuf,
 ) -> Result<(), LedgerError> {
-    let out = Command::new("python3")
-        .current_dir(dir)
-        .args(["-m", "ledgerblue.runScript"])
-        .args(["--targetId", params["targetId"].as_str()])
-        .args(["--fileName", out_file_name.as_str()])
-        .args(["--apdu", "--scp"])
-        .output()?;
+    let mut cmd = ledgerblue_install_command(dir, params, out_file_name);
+    let out = output_streamed(&mut cmd, Step::Load)?;
     if !out.status.success() {
-        return Err(LedgerError::CommandFailure {
-            cmd: "python3 -m ledgerblue.runScript",
-            status: out.status.code(),
-            stderr: String::from_utf8_lossy(&out.stderr).into(),
-        });
+        return Err(LedgerError::command_failure(
+            &cmd,
+            out.status.code(),
+            &out.stderr,
+        ));
     }
-    io::stdout().write_all(&out.stdout)?;
-    io::stderr().write_all(&out.stderr)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn python_versions() {
+        assert_eq!(
+            parse_python_version("0.1.54"),
+            Some(Version::new(0, 1, 54))
+        );
+        assert_eq!(
+            parse_python_version("0.1.54.post1\n"),
+            Some(Version::new(0, 1, 54))
+        );
+        assert_eq!(
+            parse_python_version("0.1.55rc1"),
+            Some(Version::new(0, 1, 55))
+        );
+        assert_eq!(parse_python_version("1"), Some(Version::new(1, 0, 0)));
+        assert_eq!(parse_python_version("unknown"), None);
+    }
+
+    #[test]
+    fn ledgerblue_minimum_version() {
+        assert!(check_ledgerblue_version(Some("0.1.48")).is_ok());
+        assert!(check_ledgerblue_version(Some("0.1.54.post1")).is_ok());
+        assert!(matches!(
+            check_ledgerblue_version(Some("0.1.40")),
+            Err(LedgerError::UnsupportedTool { .. })
+        ));
+        assert!(matches!(
+            check_ledgerblue_version(None),
+            Err(LedgerError::UnsupportedTool { .. })
+        ));
+    }
+}
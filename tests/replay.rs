@@ -0,0 +1,95 @@
+//! Build pipeline run end to end with the commands replayed from
+//! `tests/replay/build.jsonl`, recorded with stub tools for the ELF of
+//! `tests/replay/build/nanox/release/app`. `{dir}` in the recording stands
+//! for the directory the fixture is copied to.
+#![cfg(unix)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const HASH: &str =
+    "6b1d6f4c4ff8ed1dbd2c3c2f0b0ad3f1f4e6b1b5e0c7d9a8f3e2d1c0b9a8f7e6";
+
+/// Copy of the fixture, removed once the test is done.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/replay");
+        let dir = std::env::temp_dir()
+            .join(format!("cargo-ledger-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        copy_dir(&src.join("build"), &dir.join("build"));
+        let recording = fs::read_to_string(src.join("build.jsonl"))
+            .unwrap()
+            .replace("{dir}", dir.to_str().unwrap());
+        fs::write(dir.join("build.jsonl"), recording).unwrap();
+        Self { dir }
+    }
+
+    /// Run `cargo ledger <args>` in the fixture, replaying its recording.
+    fn cargo_ledger(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_cargo-ledger"))
+            .arg("ledger")
+            .args(["--color", "never"])
+            .args(args)
+            .current_dir(&self.dir)
+            .env("CARGO_LEDGER_REPLAY", self.dir.join("build.jsonl"))
+            .env_remove("CARGO_LEDGER_RECORD")
+            .env_remove("CARGO_LEDGER_TOOLS")
+            .env_remove("LEDGER_PYTHON")
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn copy_dir(src: &Path, dest: &Path) {
+    fs::create_dir_all(dest).unwrap();
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let dest = dest.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &dest);
+        } else {
+            fs::copy(entry.path(), dest).unwrap();
+        }
+    }
+}
+
+#[test]
+fn build_replayed() {
+    let fixture = Fixture::new("build");
+    let out = fixture.cargo_ledger(&["build", "nanox"]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success(),
+        "{stdout}{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    // Tool output is passed through
+    assert!(stdout.contains("filename"));
+    assert!(stdout.contains(&format!("Application full hash : {HASH}")));
+    // The hash printed by ledgerblue is stored next to the APDU file
+    let sha256 = fixture.dir.join("build/nanox/release/app.sha256");
+    assert_eq!(fs::read_to_string(sha256).unwrap(), format!("{HASH}\n"));
+}
+
+#[test]
+fn build_replay_mismatch() {
+    let fixture = Fixture::new("mismatch");
+    // cargo is run with an argument that was not recorded
+    let out = fixture.cargo_ledger(&["build", "nanox", "--", "--locked"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Replay: expected `cargo build"), "{stderr}");
+}
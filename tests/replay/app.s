@@ -0,0 +1,22 @@
+# Sources of build/nanox/release/app, a minimal ELF carrying the sections and
+# symbols cargo-ledger reads from Ledger apps. Built with:
+#   as -o app.o app.s && ld -N -o build/nanox/release/app app.o
+    .text
+    .globl _start
+_start:
+    ret
+    .section ledger.app_name,"a"
+    .ascii "Replay\n"
+    .section ledger.app_version,"a"
+    .ascii "1.0.0\n"
+    .section ledger.api_level,"a"
+    .ascii "22\n"
+    .section ledger.app_flags,"a"
+    .ascii "0x200\n"
+    .section ledger.target_id,"a"
+    .ascii "0x33000004\n"
+    .globl _nvram_data, _envram_data, _install_parameters, _einstall_parameters
+    .set _nvram_data, 0x1000
+    .set _envram_data, 0x1400
+    .set _install_parameters, 0x2000
+    .set _einstall_parameters, 0x2040
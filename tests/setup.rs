@@ -0,0 +1,239 @@
+//! `cargo ledger setup --local` run against a `file://` mirror of the SDK,
+//! serving `tests/setup/target.json` for every device and
+//! `tests/setup/link_wrap.sh`.
+#![cfg(unix)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const DEVICES: [&str; 5] = ["nanox", "nanosplus", "stax", "flex", "apex_p"];
+
+/// Project and SDK mirror, removed once the test is done.
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/setup");
+        let dir = std::env::temp_dir()
+            .join(format!("cargo-ledger-setup-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for device in DEVICES {
+            let devices = dir.join("mirror/devices").join(device);
+            fs::create_dir_all(&devices).unwrap();
+            fs::copy(
+                src.join("target.json"),
+                devices.join(format!("{device}.json")),
+            )
+            .unwrap();
+        }
+        fs::copy(src.join("link_wrap.sh"), dir.join("mirror/link_wrap.sh"))
+            .unwrap();
+
+        fs::create_dir_all(dir.join("app/src")).unwrap();
+        fs::write(
+            dir.join("app/Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2024\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("app/src/main.rs"), "fn main() {}\n").unwrap();
+        Self { dir }
+    }
+
+    /// Run `cargo ledger setup <args>` in the project, with the mirror.
+    fn setup(&self, args: &[&str]) -> Output {
+        let url = format!("file://{}", self.dir.join("mirror").display());
+        self.cargo_ledger(&[
+            &["setup"],
+            args,
+            &["--local", "--tag", "master", "--base-url", &url],
+        ])
+    }
+
+    /// Run `cargo ledger setup uninstall <args>` in the project.
+    fn uninstall(&self, args: &[&str]) -> Output {
+        self.cargo_ledger(&[&["setup", "uninstall"], args, &["--local"]])
+    }
+
+    fn cargo_ledger(&self, args: &[&[&str]]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_cargo-ledger"))
+            .args(["ledger", "--color", "never"])
+            .args(args.concat())
+            .current_dir(self.dir.join("app"))
+            // Files are looked for in the project's target directory
+            .env_remove("CARGO_TARGET_DIR")
+            .env_remove("CARGO_BUILD_TARGET_DIR")
+            .env_remove("CARGO_LEDGER_SDK_URL")
+            .env_remove("CARGO_LEDGER_REPLAY")
+            .env_remove("CARGO_LEDGER_RECORD")
+            .output()
+            .unwrap()
+    }
+
+    /// Path of an installed file.
+    fn installed(&self, file: &str) -> PathBuf {
+        self.dir.join("app/target/ledger-targets").join(file)
+    }
+
+    fn mirrored(&self, device: &str) -> PathBuf {
+        self.dir
+            .join("mirror/devices")
+            .join(device)
+            .join(format!("{device}.json"))
+    }
+
+    /// Change the target of `device` in the SDK, keeping it valid.
+    fn change_upstream(&self, device: &str) {
+        let path = self.mirrored(device);
+        let target = fs::read_to_string(&path).unwrap().replace(
+            "\"frame-pointer\": \"always\"",
+            "\"frame-pointer\": \"may-omit\"",
+        );
+        fs::write(path, target).unwrap();
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn assert_success(out: &Output) {
+    assert!(
+        out.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    );
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+fn check_reports_differences() {
+    let fixture = Fixture::new("check");
+    // Nothing is installed yet
+    let out = fixture.setup(&["--check"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("0 outdated, 0 locally modified, 6 missing"));
+
+    assert_success(&fixture.setup(&[]));
+    assert_success(&fixture.setup(&["--check"]));
+
+    fs::write(fixture.installed("nanox.json"), "{}").unwrap();
+    fixture.change_upstream("stax");
+    fs::remove_file(fixture.installed("flex.json")).unwrap();
+    let out = fixture.setup(&["--check"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(
+        stderr(&out).contains("1 outdated, 1 locally modified, 1 missing"),
+        "{}",
+        stderr(&out)
+    );
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("nanox.json: locally modified"));
+    assert!(stdout.contains("stax.json: outdated, changed upstream"));
+    assert!(stdout.contains("flex.json: not installed"));
+    // Nothing was written
+    assert_eq!(
+        fs::read_to_string(fixture.installed("nanox.json")).unwrap(),
+        "{}"
+    );
+    assert!(!fixture.installed("flex.json").exists());
+}
+
+#[test]
+fn modified_files_need_force() {
+    let fixture = Fixture::new("force");
+    assert_success(&fixture.setup(&[]));
+    fs::write(fixture.installed("nanox.json"), "{}").unwrap();
+    fixture.change_upstream("stax");
+
+    let out = fixture.setup(&[]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("Refusing to overwrite 1 locally modified"));
+    // Nothing is updated, not even the outdated file
+    assert_eq!(
+        fs::read_to_string(fixture.installed("nanox.json")).unwrap(),
+        "{}"
+    );
+    assert_ne!(
+        fs::read(fixture.installed("stax.json")).unwrap(),
+        fs::read(fixture.mirrored("stax")).unwrap()
+    );
+
+    assert_success(&fixture.setup(&["--force"]));
+    assert_eq!(
+        fs::read(fixture.installed("nanox.json")).unwrap(),
+        fs::read(fixture.mirrored("nanox")).unwrap()
+    );
+    assert_eq!(
+        fs::read(fixture.installed("stax.json")).unwrap(),
+        fs::read(fixture.mirrored("stax")).unwrap()
+    );
+    assert_success(&fixture.setup(&["--check"]));
+}
+
+#[test]
+fn unrecorded_files_are_saved() {
+    let fixture = Fixture::new("unrecorded");
+    // Installed by hand, or by a version of setup without a manifest
+    fs::create_dir_all(fixture.installed("")).unwrap();
+    fs::write(fixture.installed("nanox.json"), "{}").unwrap();
+
+    let out = fixture.setup(&[]);
+    assert_success(&out);
+    assert!(stderr(&out).contains("saving it as"), "{}", stderr(&out));
+    assert_eq!(
+        fs::read_to_string(fixture.installed("nanox.json.orig")).unwrap(),
+        "{}"
+    );
+    assert_eq!(
+        fs::read(fixture.installed("nanox.json")).unwrap(),
+        fs::read(fixture.mirrored("nanox")).unwrap()
+    );
+}
+
+#[test]
+fn devices_are_merged_in_the_manifest() {
+    let fixture = Fixture::new("devices");
+    assert_success(&fixture.setup(&["--device", "nanox"]));
+    assert_success(&fixture.setup(&["--device", "stax"]));
+    assert!(!fixture.installed("flex.json").exists());
+
+    let manifest =
+        fs::read_to_string(fixture.installed("cargo-ledger-setup.json"))
+            .unwrap();
+    for file in ["nanox.json", "stax.json", "link_wrap.sh"] {
+        let path = fixture.installed(file);
+        assert!(manifest.contains(path.to_str().unwrap()), "{manifest}");
+    }
+    assert_success(&fixture.setup(&["--check", "--device", "nanox"]));
+
+    // Both devices are removed
+    assert_success(&fixture.uninstall(&[]));
+    assert!(!fixture.installed("nanox.json").exists());
+    assert!(!fixture.installed("stax.json").exists());
+}
+
+#[test]
+fn uninstall_keeps_modified_files() {
+    let fixture = Fixture::new("uninstall");
+    assert_success(&fixture.setup(&[]));
+    fs::write(fixture.installed("nanox.json"), "{}").unwrap();
+
+    let out = fixture.uninstall(&[]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("Refusing to remove 1 locally modified"));
+    assert!(fixture.installed("nanox.json").exists());
+    assert!(fixture.installed("stax.json").exists());
+
+    assert_success(&fixture.uninstall(&["--force"]));
+    assert!(!fixture.installed("nanox.json").exists());
+    assert!(!fixture.installed("cargo-ledger-setup.json").exists());
+}